@@ -0,0 +1,92 @@
+// A native procedure that sorts a memory block with a CVMA procedure as the
+// comparator, calling back into the program for every comparison.
+//
+//   cargo run --example callbacks
+use libkrem::natives::peek;
+use libkrem::vm::Vm;
+
+const PROGRAM: &str = "@CVMA 1
+@Procedura 1 \"rosnąco\" 2
+ZMIENNA.K 0
+ZMIENNA.K 1
+MNIEJ.C
+ZMIENNA.U 0
+WRÓĆ
+
+@Procedura 2 \"malejąco\" 2
+ZMIENNA.K 1
+ZMIENNA.K 0
+MNIEJ.C
+ZMIENNA.U 0
+WRÓĆ
+
+@Procedura 3 \"zły\" 2
+ZMIENNA.K 0
+PCHNIJ 0
+DZIEL.C
+ZMIENNA.U 0
+WRÓĆ
+
+@Procedura 4 \"sortuj\" 3
+NAT 100
+WRÓĆ
+";
+
+const VALUES: [u64; 5] = [15, 4, 42, 8, 16];
+
+fn main() {
+    let cvma_file = libkrem::parse::read_from_string(PROGRAM);
+    assert!(cvma_file.errors.is_empty());
+
+    let mut vm = Vm::new(cvma_file.procedures);
+
+    // Sortuj(block, length, comparator), the comparator returns 1 if its
+    // first argument goes before the second one
+    vm.register_native_procedure(0x100, "Sortuj", |context| {
+        let comparator = peek(context.stack, 0);
+        let length = peek(context.stack, 1);
+        let block = peek(context.stack, 2);
+
+        for i in 1..length {
+            for j in (1..=i).rev() {
+                let a = context.allocation_array.read(block, j - 1)?;
+                let b = context.allocation_array.read(block, j)?;
+
+                if context.call(comparator, &[b, a])? != Some(1) {
+                    break;
+                }
+
+                context.allocation_array.write(block, j - 1, b)?;
+                context.allocation_array.write(block, j, a)?;
+            }
+        }
+
+        Ok(())
+    });
+
+    let block = vm.allocation_array.alloc(VALUES.len() as u64, None).unwrap();
+
+    for comparator in [1, 2, 3] {
+        for (index, value) in VALUES.iter().enumerate() {
+            vm.allocation_array
+                .write(block, index as u64, *value)
+                .unwrap();
+        }
+
+        match vm.call_by_name("sortuj", &[block, VALUES.len() as u64, comparator]) {
+            Ok(_) => {
+                let sorted: Vec<u64> = (0..VALUES.len() as u64)
+                    .map(|index| vm.allocation_array.read(block, index).unwrap())
+                    .collect();
+                println!("comparator {}: {:?}", comparator, sorted);
+            }
+            Err(error) => println!(
+                "comparator {}: {}:{}: {}",
+                comparator,
+                error.position.line,
+                error.position.column,
+                error.kind.get_message()
+            ),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// Runs a CVMA program a few instructions per frame of a simulated game loop,
+// supplying its input only every few frames.
+//
+//   cargo run --example cooperative
+use libkrem::natives::{InputBuffer, Io, OutputBuffer};
+use libkrem::vm::{Status, Vm};
+use std::io::BufReader;
+
+// Reads numbers until 0 and prints their sum
+const PROGRAM: &str = "@CVMA 1
+@Procedura 0 \"main\" 0
+PCHNIJ 0
+NAT 3
+ZMIENNA.K 1
+IDŹDO.ZE d6
+DODAJ.C
+IDŹDO d1
+USUŃ
+NAT 0
+STOP
+WRÓĆ
+";
+
+const INSTRUCTIONS_PER_FRAME: u64 = 3;
+
+fn main() {
+    let cvma_file = libkrem::parse::read_from_string(PROGRAM);
+    assert!(cvma_file.errors.is_empty());
+
+    let input = InputBuffer::default();
+    let output = OutputBuffer::default();
+    let mut vm = Vm::builder(cvma_file.procedures)
+        .io(Io {
+            input: Box::new(BufReader::new(input.clone())),
+            output: Box::new(output.clone()),
+        })
+        .build();
+
+    vm.start(0).unwrap();
+
+    let mut numbers = ["4", "8", "15", "16", "23", "42", "0"].iter();
+
+    for frame in 0.. {
+        // Input arrives every fourth frame
+        if frame % 4 == 0 {
+            if let Some(number) = numbers.next() {
+                input.push_str(&format!("{}\n", number));
+            }
+        }
+
+        match vm.run_for(INSTRUCTIONS_PER_FRAME) {
+            Ok(Status::Paused) => println!("frame {}: running", frame),
+            Ok(Status::WaitingForInput) => println!("frame {}: waiting for input", frame),
+            Ok(Status::Halted) => {
+                println!("frame {}: halted, output {}", frame, output.take_string());
+                break;
+            }
+            Ok(Status::OutOfFuel) => unreachable!("the VM is not metered"),
+            Err(error) => {
+                println!("frame {}: {}", frame, error.kind.get_message());
+                break;
+            }
+        }
+    }
+}
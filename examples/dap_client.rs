@@ -0,0 +1,166 @@
+// Scripted Debug Adapter Protocol client for trying out `kremowkuj dap`
+// without an editor. It launches the program, sets the breakpoints, then on
+// every stop inspects the stack frames and variables and steps in, over, out
+// and continues until the program terminates. Every message is printed.
+//
+//   cargo run --example dap_client -- target/debug/kremowkuj program.cvma 12 20
+use serde_json::{json, Value};
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{ChildStdin, ChildStdout, Command, Stdio};
+
+struct Client {
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    seq: u64,
+}
+
+impl Client {
+    fn send(&mut self, command: &str, arguments: Value) -> u64 {
+        self.seq += 1;
+
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        println!("-> {}", body);
+
+        write!(self.input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.input.flush().unwrap();
+        self.seq
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut content_length = 0;
+
+        loop {
+            let mut header = String::new();
+            if self.output.read_line(&mut header).unwrap() == 0 {
+                panic!("the server closed the connection");
+            }
+
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some(length) = header.strip_prefix("Content-Length:") {
+                content_length = length.trim().parse().unwrap();
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        self.output.read_exact(&mut body).unwrap();
+
+        let message: Value = serde_json::from_slice(&body).unwrap();
+        println!("<- {}", message);
+        message
+    }
+
+    // Sends a request and returns its response, events on the way are printed.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let seq = self.send(command, arguments);
+
+        loop {
+            let message = self.receive();
+
+            if message["type"] == "response" && message["request_seq"] == seq {
+                return message;
+            }
+        }
+    }
+
+    // Waits for the program to stop, returns false once it terminated.
+    fn wait_for_stop(&mut self) -> bool {
+        loop {
+            let message = self.receive();
+
+            match message["event"].as_str() {
+                Some("stopped") => return true,
+                Some("terminated") => return false,
+                _ => {}
+            }
+        }
+    }
+
+    fn inspect(&mut self) {
+        self.request("threads", json!({}));
+
+        self.request("stackTrace", json!({ "threadId": 1 }));
+
+        let scopes = self.request("scopes", json!({ "frameId": 0 }));
+
+        for scope in scopes["body"]["scopes"].as_array().unwrap() {
+            let variables = self.request(
+                "variables",
+                json!({ "variablesReference": scope["variablesReference"] }),
+            );
+
+            // Expands the first heap block, if any
+            for variable in variables["body"]["variables"].as_array().unwrap() {
+                if variable["variablesReference"].as_u64().unwrap_or(0) != 0 {
+                    self.request(
+                        "variables",
+                        json!({ "variablesReference": variable["variablesReference"] }),
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 3 {
+        println!(
+            "Usage: {} kremowkuj-binary program [breakpoint lines...]",
+            args[0]
+        );
+        return;
+    }
+
+    let mut server = Command::new(&args[1])
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("cannot start the server");
+
+    let mut client = Client {
+        input: server.stdin.take().unwrap(),
+        output: BufReader::new(server.stdout.take().unwrap()),
+        seq: 0,
+    };
+
+    let program = &args[2];
+    let breakpoints: Vec<Value> = args[3..]
+        .iter()
+        .map(|line| json!({ "line": line.parse::<u64>().unwrap() }))
+        .collect();
+
+    client.request("initialize", json!({ "adapterID": "kremowkuj" }));
+    client.request("launch", json!({ "program": program }));
+    client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": program }, "breakpoints": breakpoints }),
+    );
+    client.request("configurationDone", json!({}));
+
+    let mut commands = ["stepIn", "next", "stepOut"].iter();
+
+    while client.wait_for_stop() {
+        client.inspect();
+        client.request(
+            commands.next().unwrap_or(&"continue"),
+            json!({ "threadId": 1 }),
+        );
+    }
+
+    client.request("disconnect", json!({}));
+    server.wait().unwrap();
+}
@@ -0,0 +1,58 @@
+// Calls CVMA procedures from Rust, sharing a memory block between them.
+//
+//   cargo run --example host_call
+use libkrem::vm::Vm;
+
+const PROGRAM: &str = "@CVMA 1
+@Procedura 1 \"utwórz\" 1
+NAT 10
+ZMIENNA.U 0
+WRÓĆ
+
+@Procedura 2 \"suma\" 2
+PCHNIJ 0
+PCHNIJ 0
+ZMIENNA.K 3
+ZMIENNA.K 1
+MNIEJ.C
+IDŹDO.ZE d19
+ZMIENNA.K 0
+ZMIENNA.K 3
+NAT 12
+ZMIENNA.K 2
+DODAJ.C
+ZMIENNA.U 2
+USUŃ
+USUŃ
+ZMIENNA.K 3
+PCHNIJ 1
+DODAJ.C
+ZMIENNA.U 3
+IDŹDO d2
+ZMIENNA.K 2
+ZMIENNA.U 0
+WRÓĆ
+";
+
+fn main() {
+    let cvma_file = libkrem::parse::read_from_string(PROGRAM);
+    assert!(cvma_file.errors.is_empty());
+
+    let mut vm = Vm::builder(cvma_file.procedures).build();
+
+    // The program allocates the block, the host fills it
+    let block = vm.call_by_name("utwórz", &[3]).unwrap().unwrap();
+    for (index, value) in [4, 8, 15].iter().enumerate() {
+        vm.allocation_array
+            .write(block, index as u64, *value)
+            .unwrap();
+    }
+
+    let sum = vm.call(2, &[block, 3]).unwrap();
+    println!("sum: {:?}", sum);
+
+    match vm.call_by_name("suma", &[block]) {
+        Ok(_) => unreachable!(),
+        Err(error) => println!("{}", error.kind.get_message()),
+    }
+}
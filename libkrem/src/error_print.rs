@@ -36,57 +36,94 @@ pub fn print_errors<T>(info: &str, path: &str, content: &str, errors: VecDeque<e
     }
 
     for error in errors {
-        let error_message = error.get_message();
-        let suggestion_message = error.get_suggestion();
-        let spaces = generate_spaces(highest_digit_count);
-
-        println!(
-            "{}{}{}",
-            info.red().bold(),
-            ": ".bold(),
-            error_message.bold()
-        );
-        println!(
-            "{}{} {}:{}:{}",
-            spaces,
-            "-->".blue().bold(),
+        print_error_at(
+            info,
             path,
-            error.position.line,
-            error.position.column
+            content,
+            &error.position,
+            error.get_message(),
+            error.get_suggestion(),
+            highest_digit_count,
         );
+    }
+}
 
-        println!("{} {}", spaces, "|".blue().bold());
-        print!(
-            "{}{} {}",
-            error.position.line.to_string().blue().bold(),
-            generate_spaces(highest_digit_count - get_count_of_digits(error.position.line)),
-            "|".blue().bold()
-        );
+pub fn print_error(
+    info: &str,
+    path: &str,
+    content: &str,
+    position: &error::Position,
+    error_message: &str,
+    suggestion_message: &str,
+) {
+    print_error_at(
+        info,
+        path,
+        content,
+        position,
+        error_message,
+        suggestion_message,
+        get_count_of_digits(position.line),
+    );
+}
 
-        println!(
-            "    {}",
-            content
-                .lines()
-                .nth((error.position.line - 1) as usize)
-                .unwrap()
-                .trim()
-        );
+fn print_error_at(
+    info: &str,
+    path: &str,
+    content: &str,
+    position: &error::Position,
+    error_message: &str,
+    suggestion_message: &str,
+    highest_digit_count: i32,
+) {
+    let spaces = generate_spaces(highest_digit_count);
 
-        println!(
-            "{} {}    {}{} {}",
-            spaces,
-            "|".blue().bold(),
-            generate_spaces(error.position.column as i32),
-            "^^".red().bold(),
-            error_message.red().bold()
-        );
+    println!(
+        "{}{}{}",
+        info.red().bold(),
+        ": ".bold(),
+        error_message.bold()
+    );
+    println!(
+        "{}{} {}:{}:{}",
+        spaces,
+        "-->".blue().bold(),
+        path,
+        position.line,
+        position.column
+    );
 
-        println!(
-            "{} {} {}: {}\n",
-            spaces,
-            "=".blue().bold(),
-            "suggestion".bold(),
-            suggestion_message
-        );
-    }
-}
\ No newline at end of file
+    println!("{} {}", spaces, "|".blue().bold());
+    print!(
+        "{}{} {}",
+        position.line.to_string().blue().bold(),
+        generate_spaces(highest_digit_count - get_count_of_digits(position.line)),
+        "|".blue().bold()
+    );
+
+    println!(
+        "    {}",
+        content
+            .lines()
+            .nth((position.line - 1) as usize)
+            .unwrap()
+            .trim()
+    );
+
+    println!(
+        "{} {}    {}{} {}",
+        spaces,
+        "|".blue().bold(),
+        generate_spaces(position.column),
+        "^^".red().bold(),
+        error_message.red().bold()
+    );
+
+    println!(
+        "{} {} {}: {}\n",
+        spaces,
+        "=".blue().bold(),
+        "suggestion".bold(),
+        suggestion_message
+    );
+}
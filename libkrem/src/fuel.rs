@@ -0,0 +1,157 @@
+use crate::error;
+use crate::parse::{self, Instruction};
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::mem::{self, Discriminant};
+
+// Fuel cost of instructions and native procedures. Everything that is not in
+// the table costs 1.
+#[derive(Clone, Debug, Default)]
+pub struct CostTable {
+    instructions: HashMap<Discriminant<Instruction>, u64>,
+    natives: HashMap<u64, u64>,
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        CostTable::default()
+    }
+
+    // The operand of `instruction` is ignored, every PCHNIJ costs the same.
+    pub fn set_instruction_cost(&mut self, instruction: Instruction, cost: u64) {
+        self.instructions
+            .insert(mem::discriminant(&instruction), cost);
+    }
+
+    // Replaces the cost of NAT for the given native procedure.
+    pub fn set_native_cost(&mut self, index: u64, cost: u64) {
+        self.natives.insert(index, cost);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty() && self.natives.is_empty()
+    }
+
+    pub fn cost_of(&self, instruction: &Instruction) -> u64 {
+        if self.is_empty() {
+            return 1;
+        }
+
+        if let Instruction::Nat(index) = instruction {
+            if let Some(&cost) = self.natives.get(index) {
+                return cost;
+            }
+        }
+
+        self.instructions
+            .get(&mem::discriminant(instruction))
+            .copied()
+            .unwrap_or(1)
+    }
+
+    // Reads a cost table, one entry per line:
+    //   DODAJ.C 2      ; mnemonic, cost (dec)
+    //   NAT 10 50      ; native procedure (hex), cost (dec)
+    pub fn read_from_string(content: &str) -> Result<CostTable, String> {
+        let mut cost_table = CostTable::new();
+
+        for (line_idx, line) in content.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let invalid_line = || format!("line {}: invalid cost table entry", line_idx + 1);
+
+            match parts.as_slice() {
+                [] => {}
+                ["NAT", index, cost] => {
+                    let index = u64::from_str_radix(index, 16).map_err(|_| invalid_line())?;
+                    let cost = cost.parse::<u64>().map_err(|_| invalid_line())?;
+
+                    cost_table.set_native_cost(index, cost);
+                }
+                [mnemonic, cost] => {
+                    let mut errors = VecDeque::new();
+                    let instruction = parse::get_instruction_from_strings(
+                        mnemonic,
+                        "0",
+                        &mut error::Position { line: 0, column: 0 },
+                        &mut errors,
+                    );
+
+                    if !errors.is_empty() {
+                        return Err(format!(
+                            "line {}: unknown instruction {}",
+                            line_idx + 1,
+                            mnemonic
+                        ));
+                    }
+
+                    let cost = cost.parse::<u64>().map_err(|_| invalid_line())?;
+                    cost_table.set_instruction_cost(instruction, cost);
+                }
+                _ => return Err(invalid_line()),
+            }
+        }
+
+        Ok(cost_table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_table_charges_one_per_instruction() {
+        let cost_table = CostTable::read_from_string("; nothing here\n\n").unwrap();
+
+        assert!(cost_table.is_empty());
+        assert_eq!(cost_table.cost_of(&Instruction::DzielC), 1);
+        assert_eq!(cost_table.cost_of(&Instruction::Nat(0x10)), 1);
+    }
+
+    #[test]
+    fn reads_instruction_and_native_costs() {
+        let cost_table =
+            CostTable::read_from_string("DZIEL.C 5 ; slow\nPCHNIJ 2\nNAT 10 50\nNAT 1F 7\n")
+                .unwrap();
+
+        assert_eq!(cost_table.cost_of(&Instruction::DzielC), 5);
+        // The operand does not matter
+        assert_eq!(cost_table.cost_of(&Instruction::Pchnij(0)), 2);
+        assert_eq!(cost_table.cost_of(&Instruction::Pchnij(0xFF)), 2);
+        // Native indices are hex, costs are decimal
+        assert_eq!(cost_table.cost_of(&Instruction::Nat(0x10)), 50);
+        assert_eq!(cost_table.cost_of(&Instruction::Nat(0x1F)), 7);
+        // Other natives cost what NAT costs, other instructions 1
+        assert_eq!(cost_table.cost_of(&Instruction::Nat(0x11)), 1);
+        assert_eq!(cost_table.cost_of(&Instruction::DodajC), 1);
+    }
+
+    #[test]
+    fn nat_entry_sets_the_cost_of_other_natives() {
+        let cost_table = CostTable::read_from_string("NAT 3\nNAT 10 50\n").unwrap();
+
+        assert_eq!(cost_table.cost_of(&Instruction::Nat(0x10)), 50);
+        assert_eq!(cost_table.cost_of(&Instruction::Nat(0x11)), 3);
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert_eq!(
+            CostTable::read_from_string("DODAJ.C 1\nSKACZ 2\n").unwrap_err(),
+            "line 2: unknown instruction SKACZ"
+        );
+        assert_eq!(
+            CostTable::read_from_string("DODAJ.C dużo\n").unwrap_err(),
+            "line 1: invalid cost table entry"
+        );
+        assert_eq!(
+            CostTable::read_from_string("NAT x 5\n").unwrap_err(),
+            "line 1: invalid cost table entry"
+        );
+        assert_eq!(
+            CostTable::read_from_string("DODAJ.C 1 2 3\n").unwrap_err(),
+            "line 1: invalid cost table entry"
+        );
+    }
+}
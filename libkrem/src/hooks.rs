@@ -0,0 +1,155 @@
+use crate::parse::Procedure;
+use std::collections::VecDeque;
+
+// Callbacks into the execution loop of the VM, for tools like tracers,
+// profilers and coverage that need to see what the program does. Every method
+// does nothing by default. The VM is generic over the hooks, so code for
+// callbacks that are not implemented is compiled out, and running without
+// hooks (`NoHooks`) is as fast as a loop that has none.
+pub trait Hooks {
+    // Called before the instruction at `pc` of `procedure` runs, after its fuel
+    // was charged. A NAT that stopped to wait for input is not reported again
+    // when it runs after the input arrived.
+    fn before_instruction(&mut self, _procedure: &Procedure, _pc: u64, _stack: &VecDeque<u64>) {}
+
+    // Called after the instruction at `pc` of `procedure` ran without an error.
+    // For WYWOŁAJ this is after the callee was entered and for WRÓĆ after the
+    // caller was returned to.
+    fn after_instruction(&mut self, _procedure: &Procedure, _pc: u64, _stack: &VecDeque<u64>) {}
+
+    // Called when WYWOŁAJ at `pc` of `caller` enters `callee`. Procedures
+    // without code are not entered, so they are not reported.
+    fn call(&mut self, _caller: &Procedure, _pc: u64, _callee: &Procedure) {}
+
+    // Called when WRÓĆ leaves `procedure`, including the entry procedure. STOP
+    // ends the program without returning from the procedures on the call stack.
+    fn ret(&mut self, _procedure: &Procedure) {}
+
+    // Called before the native procedure with the given index and name runs.
+    // Like `before_instruction`, not again when it runs after waiting for input.
+    fn native_call(&mut self, _index: u64, _name: &'static str, _stack: &VecDeque<u64>) {}
+
+    // Called after a native procedure allocated a memory block.
+    fn heap_alloc(&mut self, _handle: u64, _size: u64) {}
+
+    // Called after a native procedure freed a memory block.
+    fn heap_free(&mut self, _handle: u64) {}
+}
+
+// Hooks that do nothing, used by `Vm::run` and `Vm::step`.
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+// Forwards every callback to both hooks, the first one first.
+impl<A: Hooks, B: Hooks> Hooks for (A, B) {
+    fn before_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        self.0.before_instruction(procedure, pc, stack);
+        self.1.before_instruction(procedure, pc, stack);
+    }
+
+    fn after_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        self.0.after_instruction(procedure, pc, stack);
+        self.1.after_instruction(procedure, pc, stack);
+    }
+
+    fn call(&mut self, caller: &Procedure, pc: u64, callee: &Procedure) {
+        self.0.call(caller, pc, callee);
+        self.1.call(caller, pc, callee);
+    }
+
+    fn ret(&mut self, procedure: &Procedure) {
+        self.0.ret(procedure);
+        self.1.ret(procedure);
+    }
+
+    fn native_call(&mut self, index: u64, name: &'static str, stack: &VecDeque<u64>) {
+        self.0.native_call(index, name, stack);
+        self.1.native_call(index, name, stack);
+    }
+
+    fn heap_alloc(&mut self, handle: u64, size: u64) {
+        self.0.heap_alloc(handle, size);
+        self.1.heap_alloc(handle, size);
+    }
+
+    fn heap_free(&mut self, handle: u64) {
+        self.0.heap_free(handle);
+        self.1.heap_free(handle);
+    }
+}
+
+// Forwards every callback to the hooks, if there are any.
+impl<H: Hooks> Hooks for Option<H> {
+    fn before_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        if let Some(hooks) = self {
+            hooks.before_instruction(procedure, pc, stack);
+        }
+    }
+
+    fn after_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        if let Some(hooks) = self {
+            hooks.after_instruction(procedure, pc, stack);
+        }
+    }
+
+    fn call(&mut self, caller: &Procedure, pc: u64, callee: &Procedure) {
+        if let Some(hooks) = self {
+            hooks.call(caller, pc, callee);
+        }
+    }
+
+    fn ret(&mut self, procedure: &Procedure) {
+        if let Some(hooks) = self {
+            hooks.ret(procedure);
+        }
+    }
+
+    fn native_call(&mut self, index: u64, name: &'static str, stack: &VecDeque<u64>) {
+        if let Some(hooks) = self {
+            hooks.native_call(index, name, stack);
+        }
+    }
+
+    fn heap_alloc(&mut self, handle: u64, size: u64) {
+        if let Some(hooks) = self {
+            hooks.heap_alloc(handle, size);
+        }
+    }
+
+    fn heap_free(&mut self, handle: u64) {
+        if let Some(hooks) = self {
+            hooks.heap_free(handle);
+        }
+    }
+}
+
+impl<H: Hooks + ?Sized> Hooks for &mut H {
+    fn before_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        (**self).before_instruction(procedure, pc, stack);
+    }
+
+    fn after_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        (**self).after_instruction(procedure, pc, stack);
+    }
+
+    fn call(&mut self, caller: &Procedure, pc: u64, callee: &Procedure) {
+        (**self).call(caller, pc, callee);
+    }
+
+    fn ret(&mut self, procedure: &Procedure) {
+        (**self).ret(procedure);
+    }
+
+    fn native_call(&mut self, index: u64, name: &'static str, stack: &VecDeque<u64>) {
+        (**self).native_call(index, name, stack);
+    }
+
+    fn heap_alloc(&mut self, handle: u64, size: u64) {
+        (**self).heap_alloc(handle, size);
+    }
+
+    fn heap_free(&mut self, handle: u64) {
+        (**self).heap_free(handle);
+    }
+}
@@ -2,4 +2,11 @@
 
 pub mod parse;
 pub mod error;
-pub mod error_print;
\ No newline at end of file
+pub mod error_print;
+pub mod fuel;
+pub mod hooks;
+pub mod memory;
+pub mod natives;
+pub mod replay;
+pub mod snapshot;
+pub mod vm;
\ No newline at end of file
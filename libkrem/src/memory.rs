@@ -0,0 +1,538 @@
+use crate::error;
+use std::cmp::Ordering;
+use std::fmt;
+
+// Memory blocks handed out by `Alloc` are identified by handles, not by their
+// position in the allocation array. The low 32 bits of a handle select a slot,
+// the high 32 bits hold the generation of that slot at the time of allocation.
+// Freeing a block bumps the generation of its slot, so a stale handle can never
+// reach the block that reuses the slot later on.
+const SLOT_BITS: u64 = 32;
+const SLOT_MASK: u64 = (1 << SLOT_BITS) - 1;
+
+#[derive(Clone, Debug)]
+pub enum MemoryError {
+    InvalidHandle(u64),
+    UseAfterFree(u64),
+    DoubleFree(u64),
+    IndexOutOfBounds {
+        handle: u64,
+        index: u64,
+        size: usize,
+    },
+    RangeOutOfBounds {
+        handle: u64,
+        index: u64,
+        count: u64,
+        size: usize,
+    },
+    HeapLimitExceeded {
+        requested: u64,
+        limit: u64,
+    },
+    OutOfMemory(u64),
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::InvalidHandle(handle) => {
+                write!(f, "{:X} is not a valid memory block", handle)
+            }
+            MemoryError::UseAfterFree(handle) => {
+                write!(f, "memory block {:X} is used after being freed", handle)
+            }
+            MemoryError::DoubleFree(handle) => {
+                write!(f, "memory block {:X} is freed twice", handle)
+            }
+            MemoryError::IndexOutOfBounds {
+                handle,
+                index,
+                size,
+            } => write!(
+                f,
+                "index {} is out of bounds for memory block {:X} of size {}",
+                index, handle, size
+            ),
+            MemoryError::RangeOutOfBounds {
+                handle,
+                index,
+                count,
+                size,
+            } => write!(
+                f,
+                "range of {} values at index {} is out of bounds for memory block {:X} of size {}",
+                count, index, handle, size
+            ),
+            MemoryError::HeapLimitExceeded { requested, limit } => write!(
+                f,
+                "allocating {} more values exceeds the heap limit of {} values",
+                requested, limit
+            ),
+            MemoryError::OutOfMemory(requested) => {
+                write!(f, "out of memory while allocating {} values", requested)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HeapStatistics {
+    pub allocation_count: u64,
+    pub live_blocks: usize,
+    pub live_words: u64,
+    pub peak_live_blocks: usize,
+    pub peak_live_words: u64,
+}
+
+// Allocations and frees in the order they happened, collected for execution
+// hooks since they are done by native procedures the VM does not look into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapEvent {
+    Alloc { handle: u64, size: u64 },
+    Free { handle: u64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct LeakedBlock {
+    pub handle: u64,
+    pub size: usize,
+    // Position of the NAT instruction that allocated the block, if any
+    pub site: Option<error::Position>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeapReport {
+    pub statistics: HeapStatistics,
+    pub leaks: Vec<LeakedBlock>,
+}
+
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let leaked_words: usize = self.leaks.iter().map(|leak| leak.size).sum();
+
+        writeln!(f, "Heap report:")?;
+        writeln!(f, "  allocations: {}", self.statistics.allocation_count)?;
+        writeln!(
+            f,
+            "  peak live blocks: {}",
+            self.statistics.peak_live_blocks
+        )?;
+        writeln!(f, "  peak live words: {}", self.statistics.peak_live_words)?;
+        write!(
+            f,
+            "  leaked blocks: {} ({} words)",
+            self.leaks.len(),
+            leaked_words
+        )?;
+
+        for leak in &self.leaks {
+            write!(f, "\n    {:X}: {} words", leak.handle, leak.size)?;
+
+            if let Some(site) = &leak.site {
+                write!(f, ", allocated at line {}", site.line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Slot {
+    pub(crate) generation: u32,
+    pub(crate) block: Option<Vec<u64>>,
+    pub(crate) site: Option<error::Position>,
+}
+
+#[derive(Clone, Default)]
+pub struct AllocationArray {
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+    statistics: HeapStatistics,
+    // Upper bound for the total number of values in live blocks
+    max_words: Option<u64>,
+    events: Vec<HeapEvent>,
+}
+
+fn make_handle(index: usize, generation: u32) -> u64 {
+    ((generation as u64) << SLOT_BITS) | index as u64
+}
+
+fn check_index(handle: u64, block: &[u64], index: u64) -> Result<usize, MemoryError> {
+    if index < block.len() as u64 {
+        Ok(index as usize)
+    } else {
+        Err(MemoryError::IndexOutOfBounds {
+            handle,
+            index,
+            size: block.len(),
+        })
+    }
+}
+
+fn check_range(
+    handle: u64,
+    block: &[u64],
+    index: u64,
+    count: u64,
+) -> Result<std::ops::Range<usize>, MemoryError> {
+    match index.checked_add(count) {
+        Some(end) if end <= block.len() as u64 => Ok(index as usize..end as usize),
+        _ => Err(MemoryError::RangeOutOfBounds {
+            handle,
+            index,
+            count,
+            size: block.len(),
+        }),
+    }
+}
+
+impl AllocationArray {
+    pub fn new() -> AllocationArray {
+        AllocationArray::default()
+    }
+
+    pub fn set_max_words(&mut self, max_words: Option<u64>) {
+        self.max_words = max_words;
+    }
+
+    pub fn alloc(&mut self, size: u64, site: Option<error::Position>) -> Result<u64, MemoryError> {
+        self.check_limit(size)?;
+
+        let mut block = Vec::new();
+        block
+            .try_reserve_exact(size as usize)
+            .map_err(|_| MemoryError::OutOfMemory(size))?;
+        block.resize(size as usize, 0);
+
+        self.statistics.allocation_count += 1;
+        self.statistics.live_blocks += 1;
+        self.statistics.live_words += size;
+        self.update_peaks();
+
+        match self.free_slots.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.block = Some(block);
+                slot.site = site;
+
+                let handle = make_handle(index as usize, slot.generation);
+                self.events.push(HeapEvent::Alloc { handle, size });
+
+                Ok(handle)
+            }
+            None => {
+                // Generations start at 1, so 0 is never a valid handle.
+                self.slots.push(Slot {
+                    generation: 1,
+                    block: Some(block),
+                    site,
+                });
+
+                let handle = make_handle(self.slots.len() - 1, 1);
+                self.events.push(HeapEvent::Alloc { handle, size });
+
+                Ok(handle)
+            }
+        }
+    }
+
+    pub fn free(&mut self, handle: u64) -> Result<(), MemoryError> {
+        let index = self.slot_index(handle).map_err(|error| match error {
+            MemoryError::UseAfterFree(handle) => MemoryError::DoubleFree(handle),
+            error => error,
+        })?;
+
+        let slot = &mut self.slots[index];
+        let size = slot.block.take().unwrap().len();
+        slot.site = None;
+
+        self.statistics.live_blocks -= 1;
+        self.statistics.live_words -= size as u64;
+
+        // A slot whose generation would overflow is retired instead of reused.
+        if slot.generation < u32::MAX {
+            slot.generation += 1;
+            self.free_slots.push(index as u32);
+        }
+
+        self.events.push(HeapEvent::Free { handle });
+
+        Ok(())
+    }
+
+    pub fn get(&self, handle: u64) -> Result<&Vec<u64>, MemoryError> {
+        let index = self.slot_index(handle)?;
+        Ok(self.slots[index].block.as_ref().unwrap())
+    }
+
+    pub fn get_mut(&mut self, handle: u64) -> Result<&mut Vec<u64>, MemoryError> {
+        let index = self.slot_index(handle)?;
+        Ok(self.slots[index].block.as_mut().unwrap())
+    }
+
+    pub fn read(&self, handle: u64, index: u64) -> Result<u64, MemoryError> {
+        let block = self.get(handle)?;
+        let index = check_index(handle, block, index)?;
+
+        Ok(block[index])
+    }
+
+    pub fn write(&mut self, handle: u64, index: u64, value: u64) -> Result<(), MemoryError> {
+        let block = self.get_mut(handle)?;
+        let index = check_index(handle, block, index)?;
+
+        block[index] = value;
+        Ok(())
+    }
+
+    pub fn len(&self, handle: u64) -> Result<usize, MemoryError> {
+        Ok(self.get(handle)?.len())
+    }
+
+    // Grows or shrinks the block in place, new values are zeroed. The handle
+    // stays valid.
+    pub fn resize(&mut self, handle: u64, size: u64) -> Result<(), MemoryError> {
+        let old_size = self.len(handle)? as u64;
+
+        if size > old_size {
+            self.check_limit(size - old_size)?;
+        }
+
+        let block = self.get_mut(handle)?;
+        block
+            .try_reserve_exact((size - old_size.min(size)) as usize)
+            .map_err(|_| MemoryError::OutOfMemory(size))?;
+        block.resize(size as usize, 0);
+
+        self.statistics.live_words = self.statistics.live_words - old_size + size;
+        self.update_peaks();
+        Ok(())
+    }
+
+    pub fn fill(
+        &mut self,
+        handle: u64,
+        index: u64,
+        count: u64,
+        value: u64,
+    ) -> Result<(), MemoryError> {
+        let block = self.get_mut(handle)?;
+        let range = check_range(handle, block, index, count)?;
+
+        block[range].iter_mut().for_each(|x| *x = value);
+        Ok(())
+    }
+
+    // Copies `count` values between blocks, overlapping ranges of a single block
+    // are handled like memmove.
+    pub fn copy(
+        &mut self,
+        destination: u64,
+        destination_index: u64,
+        source: u64,
+        source_index: u64,
+        count: u64,
+    ) -> Result<(), MemoryError> {
+        let source_block = self.get(source)?;
+        let source_range = check_range(source, source_block, source_index, count)?;
+
+        if self.slot_index(source)? == self.slot_index(destination)? {
+            let block = self.get_mut(destination)?;
+            let destination_range = check_range(destination, block, destination_index, count)?;
+
+            block.copy_within(source_range, destination_range.start);
+        } else {
+            let values = source_block[source_range].to_vec();
+            let block = self.get_mut(destination)?;
+            let destination_range = check_range(destination, block, destination_index, count)?;
+
+            block[destination_range].copy_from_slice(&values);
+        }
+
+        Ok(())
+    }
+
+    // Lexicographic comparison of two ranges of unsigned values.
+    pub fn compare(
+        &self,
+        first: u64,
+        first_index: u64,
+        second: u64,
+        second_index: u64,
+        count: u64,
+    ) -> Result<Ordering, MemoryError> {
+        let first_block = self.get(first)?;
+        let first_range = check_range(first, first_block, first_index, count)?;
+        let second_block = self.get(second)?;
+        let second_range = check_range(second, second_block, second_index, count)?;
+
+        Ok(first_block[first_range].cmp(&second_block[second_range]))
+    }
+
+    // Live blocks together with their handles, in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Vec<u64>)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.block
+                .as_ref()
+                .map(|block| (make_handle(index, slot.generation), block))
+        })
+    }
+
+    // Removes and returns the allocations and frees since the last call.
+    pub fn take_events(&mut self) -> std::vec::Drain<'_, HeapEvent> {
+        self.events.drain(..)
+    }
+
+    pub fn statistics(&self) -> &HeapStatistics {
+        &self.statistics
+    }
+
+    // Statistics together with every block that is still allocated.
+    pub fn report(&self) -> HeapReport {
+        let leaks = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                slot.block.as_ref().map(|block| LeakedBlock {
+                    handle: make_handle(index, slot.generation),
+                    size: block.len(),
+                    site: slot.site.clone(),
+                })
+            })
+            .collect();
+
+        HeapReport {
+            statistics: self.statistics.clone(),
+            leaks,
+        }
+    }
+
+    // Slots and the free list in reuse order, as stored in snapshots.
+    pub(crate) fn slots(&self) -> (&[Slot], &[u32]) {
+        (&self.slots, &self.free_slots)
+    }
+
+    // Replaces the blocks with the ones from a snapshot, keeping the limit. The
+    // live counts are recomputed from the slots.
+    pub(crate) fn restore_slots(
+        &mut self,
+        slots: Vec<Slot>,
+        free_slots: Vec<u32>,
+        statistics: HeapStatistics,
+    ) {
+        let live = slots.iter().filter_map(|slot| slot.block.as_ref());
+
+        self.statistics = HeapStatistics {
+            live_blocks: live.clone().count(),
+            live_words: live.map(|block| block.len() as u64).sum(),
+            ..statistics
+        };
+        self.slots = slots;
+        self.free_slots = free_slots;
+        self.events.clear();
+    }
+
+    fn check_limit(&self, requested: u64) -> Result<(), MemoryError> {
+        match self.max_words {
+            Some(limit) if self.statistics.live_words.saturating_add(requested) > limit => {
+                Err(MemoryError::HeapLimitExceeded { requested, limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update_peaks(&mut self) {
+        let statistics = &mut self.statistics;
+
+        statistics.peak_live_blocks = statistics.peak_live_blocks.max(statistics.live_blocks);
+        statistics.peak_live_words = statistics.peak_live_words.max(statistics.live_words);
+    }
+
+    fn slot_index(&self, handle: u64) -> Result<usize, MemoryError> {
+        let index = (handle & SLOT_MASK) as usize;
+        let generation = (handle >> SLOT_BITS) as u32;
+
+        match self.slots.get(index) {
+            Some(slot) if generation != 0 && generation <= slot.generation => {
+                if generation == slot.generation && slot.block.is_some() {
+                    Ok(index)
+                } else {
+                    Err(MemoryError::UseAfterFree(handle))
+                }
+            }
+            _ => Err(MemoryError::InvalidHandle(handle)),
+        }
+    }
+}
+
+impl fmt::Debug for AllocationArray {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_handle_is_stale() {
+        let mut array = AllocationArray::new();
+        let handle = array.alloc(2, None).unwrap();
+        array.free(handle).unwrap();
+
+        assert!(matches!(array.read(handle, 0), Err(MemoryError::UseAfterFree(h)) if h == handle));
+        assert!(matches!(array.free(handle), Err(MemoryError::DoubleFree(h)) if h == handle));
+    }
+
+    #[test]
+    fn reused_slot_does_not_accept_the_old_handle() {
+        let mut array = AllocationArray::new();
+        let old = array.alloc(2, None).unwrap();
+        array.free(old).unwrap();
+
+        let new = array.alloc(2, None).unwrap();
+        assert_eq!(new & SLOT_MASK, old & SLOT_MASK);
+        assert_ne!(new, old);
+
+        array.write(new, 0, 7).unwrap();
+        assert!(matches!(
+            array.write(old, 0, 8),
+            Err(MemoryError::UseAfterFree(_))
+        ));
+        assert!(matches!(array.free(old), Err(MemoryError::DoubleFree(_))));
+        assert_eq!(array.read(new, 0).unwrap(), 7);
+
+        // A generation the slot never had is not a handle at all
+        let future = make_handle(0, 3);
+        assert!(matches!(
+            array.read(future, 0),
+            Err(MemoryError::InvalidHandle(_))
+        ));
+    }
+
+    #[test]
+    fn slot_at_the_last_generation_is_retired() {
+        let mut array = AllocationArray::new();
+        array.alloc(1, None).unwrap();
+        array.slots[0].generation = u32::MAX;
+
+        let last = make_handle(0, u32::MAX);
+        array.free(last).unwrap();
+        assert!(matches!(
+            array.read(last, 0),
+            Err(MemoryError::UseAfterFree(_))
+        ));
+
+        let next = array.alloc(1, None).unwrap();
+        assert_eq!(next, make_handle(1, 1));
+        assert!(matches!(
+            array.read(last, 0),
+            Err(MemoryError::UseAfterFree(_))
+        ));
+        assert_eq!(array.statistics().live_blocks, 1);
+    }
+}
@@ -0,0 +1,532 @@
+use crate::error;
+use crate::hooks::Hooks;
+use crate::memory::{AllocationArray, MemoryError};
+use crate::vm::{Interpreter, RuntimeError};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+#[repr(u64)]
+pub enum ReservedNativeProcedures {
+    // 0x - I/O
+    PutC = 0x00,
+    PutZ = 0x01,
+    PutU = 0x02,
+    GetC = 0x03,
+    GetZ = 0x04,
+    GetU = 0x05,
+
+    // 1x - memory
+    Alloc = 0x10,
+    Free = 0x11,
+    Read = 0x12,
+    Write = 0x13,
+
+    // 2x - strings
+    Print = 0x20,
+
+    // 11x - memory, implementation-specific. All ranges are bounds-checked.
+    // (parameters: 1) block -> pushes the number of values in the block
+    Length = 0x110,
+    // (parameters: 2) block, size -> grows or shrinks the block in place,
+    // new values are zeroed
+    Realloc = 0x111,
+    // (parameters: 5) destination block, destination index, source block,
+    // source index, count -> copies the values, ranges may overlap
+    Copy = 0x112,
+    // (parameters: 4) block, index, count, value -> sets the values
+    Fill = 0x113,
+    // (parameters: 5) first block, first index, second block, second index,
+    // count -> pushes -1, 0 or 1 comparing the ranges as unsigned values
+    Compare = 0x114,
+
+    // 12x - assertions, implementation-specific. A failed assertion stops the
+    // program with a runtime error. The message block holds a string like the
+    // one printed by Print, 0 means no message.
+    // (parameters: 2) value, message block -> fails if the value is 0
+    AssertTrue = 0x120,
+    // (parameters: 3) actual, expected, message block -> compares as integers
+    AssertEqualC = 0x121,
+    // (parameters: 3) actual, expected, message block -> compares as floats,
+    // NaN is equal to NaN
+    AssertEqualZ = 0x122,
+}
+
+// Where the I/O native procedures read from and write to. By default these are
+// the standard input and output of the process.
+pub struct Io {
+    pub input: Box<dyn BufRead>,
+    pub output: Box<dyn Write>,
+}
+
+// Standard input read a line at a time. Nothing past the current line is
+// buffered, so other readers of stdin (like a debugger prompt) are not starved.
+#[derive(Default)]
+pub struct StdinLines {
+    line: Vec<u8>,
+    consumed: usize,
+}
+
+impl io::Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed == self.line.len() {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+
+            self.line = line.into_bytes();
+            self.consumed = 0;
+        }
+
+        Ok(&self.line[self.consumed..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed = (self.consumed + amount).min(self.line.len());
+    }
+}
+
+// In-memory output that stays readable while the VM owns a clone of it
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    // Removes the output written so far, invalid UTF-8 is replaced.
+    pub fn take_string(&self) -> String {
+        let bytes = self.0.replace(Vec::new());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+// Input supplied by the host while the program runs, for example between calls
+// of `Vm::run_for`. Only complete lines can be read, until they arrive reading
+// fails with WouldBlock and the VM stops with `Status::WaitingForInput`. Once
+// closed, the rest is read and then the input ends. The VM reads it through a
+// `BufReader` owning a clone of it.
+#[derive(Clone, Default)]
+pub struct InputBuffer(Rc<RefCell<InputBufferState>>);
+
+#[derive(Default)]
+struct InputBufferState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl InputBuffer {
+    pub fn push_str(&self, input: &str) {
+        self.0.borrow_mut().data.extend(input.bytes());
+    }
+
+    // Marks the end of the input.
+    pub fn close(&self) {
+        self.0.borrow_mut().closed = true;
+    }
+}
+
+impl io::Read for InputBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+
+        // Past the last newline is an incomplete line, unless no more input comes
+        let available = match state.data.iter().rposition(|&byte| byte == b'\n') {
+            _ if state.closed => state.data.len(),
+            Some(newline) => newline + 1,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+
+        let count = available.min(buf.len());
+        for (target, byte) in buf.iter_mut().zip(state.data.drain(..count)) {
+            *target = byte;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Default for Io {
+    fn default() -> Io {
+        Io {
+            input: Box::new(StdinLines::default()),
+            output: Box::new(io::stdout()),
+        }
+    }
+}
+
+// State of the VM a native procedure is allowed to touch
+pub struct NativeContext<'a> {
+    pub stack: &'a mut VecDeque<u64>,
+    pub allocation_array: &'a mut AllocationArray,
+    pub io: &'a mut Io,
+    // Position of the NAT instruction calling the native procedure
+    pub position: &'a error::Position,
+    // The rest of the VM, for calling procedures with `call`
+    pub(crate) interpreter: Interpreter<'a>,
+    pub(crate) hooks: &'a mut dyn Hooks,
+}
+
+#[derive(Clone, Debug)]
+pub enum NativeError {
+    Memory(MemoryError),
+    InvalidInput(&'static str),
+    // The memory block does not hold a UTF-8 string
+    InvalidString(u64),
+    EndOfInput,
+    // The input has no complete line yet, the host has to supply more
+    InputNotReady,
+    Input(io::ErrorKind),
+    Output(io::ErrorKind),
+    AssertionFailed(String),
+    // Error of the recorded call being replayed
+    Replayed(String),
+    ReplayDiverged(String),
+    // Error of a procedure called with `NativeContext::call`
+    Call(Box<RuntimeError>),
+}
+
+impl From<MemoryError> for NativeError {
+    fn from(error: MemoryError) -> NativeError {
+        NativeError::Memory(error)
+    }
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NativeError::Memory(error) => error.fmt(f),
+            NativeError::InvalidInput(expected) => {
+                write!(f, "got invalid input, expected {}", expected)
+            }
+            NativeError::InvalidString(handle) => write!(
+                f,
+                "memory block {:X} does not hold a valid UTF-8 string",
+                handle
+            ),
+            NativeError::EndOfInput => write!(f, "reached the end of input"),
+            NativeError::InputNotReady => write!(f, "the input is not ready yet"),
+            NativeError::Input(kind) => write!(f, "failed to read input: {}", kind),
+            NativeError::Output(kind) => write!(f, "failed to write output: {}", kind),
+            NativeError::AssertionFailed(message) => write!(f, "assertion failed: {}", message),
+            NativeError::Replayed(message) => write!(f, "{}", message),
+            NativeError::ReplayDiverged(message) => write!(f, "replay diverged, {}", message),
+            NativeError::Call(error) => write!(f, "{}", error.kind.get_message()),
+        }
+    }
+}
+
+pub type NativeProcedureFn = dyn Fn(&mut NativeContext) -> Result<(), NativeError>;
+
+pub struct NativeProcedure {
+    pub name: &'static str,
+    pub function: Box<NativeProcedureFn>,
+    // False for native procedures whose results can differ between runs, like
+    // the ones reading input. Only their results are recorded and replayed.
+    pub deterministic: bool,
+}
+
+pub type NativeProceduresMap = HashMap<u64, NativeProcedure>;
+
+macro_rules! register_native_procedure {
+    ($natprocs:expr, $name:ident, $procedure:expr) => {
+        register_native_procedure!($natprocs, $name, true, $procedure)
+    };
+    ($natprocs:expr, $name:ident, $deterministic:expr, $procedure:expr) => {
+        $natprocs.insert(
+            ReservedNativeProcedures::$name as u64,
+            NativeProcedure {
+                name: stringify!($name),
+                function: Box::new($procedure),
+                deterministic: $deterministic,
+            },
+        );
+    };
+}
+
+// Reads a line including the newline.
+fn get_stdin_input(io: &mut Io) -> Result<String, NativeError> {
+    let mut buffer = String::new();
+
+    match io.input.read_line(&mut buffer) {
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+            return Err(NativeError::InputNotReady)
+        }
+        Err(error) => return Err(NativeError::Input(error.kind())),
+        Ok(_) => {}
+    }
+
+    if buffer.is_empty() {
+        Err(NativeError::EndOfInput)
+    } else {
+        Ok(buffer)
+    }
+}
+
+fn write_output(io: &mut Io, args: fmt::Arguments) -> Result<(), NativeError> {
+    io.output
+        .write_fmt(args)
+        .map_err(|error| NativeError::Output(error.kind()))
+}
+
+pub fn peek(stack: &VecDeque<u64>, n: usize) -> u64 {
+    *stack.get((stack.len() - 1) - n).unwrap()
+}
+
+fn register_natproc_io(native_procedures: &mut NativeProceduresMap) {
+    register_native_procedure!(native_procedures, PutC, |context| {
+        write_output(context.io, format_args!("{}", peek(context.stack, 0)))
+    });
+
+    register_native_procedure!(native_procedures, PutZ, |context| {
+        write_output(
+            context.io,
+            format_args!("{}", f64::from_bits(peek(context.stack, 0))),
+        )
+    });
+
+    register_native_procedure!(native_procedures, PutU, |context| {
+        write_output(
+            context.io,
+            format_args!("{}", char::from_u32(peek(context.stack, 0) as u32).unwrap()),
+        )
+    });
+
+    register_native_procedure!(native_procedures, GetC, false, |context| {
+        match get_stdin_input(context.io)?.trim().parse::<u64>() {
+            Ok(value) => {
+                context.stack.push_back(value);
+                Ok(())
+            }
+            Err(_) => Err(NativeError::InvalidInput("u64")),
+        }
+    });
+
+    register_native_procedure!(native_procedures, GetZ, false, |context| {
+        match get_stdin_input(context.io)?.trim().parse::<f64>() {
+            Ok(value) => {
+                context.stack.push_back(value.to_bits());
+                Ok(())
+            }
+            Err(_) => Err(NativeError::InvalidInput("f64")),
+        }
+    });
+
+    register_native_procedure!(native_procedures, GetU, false, |context| {
+        let input = get_stdin_input(context.io)?;
+
+        context
+            .stack
+            .push_back(input.chars().next().unwrap() as u64);
+        Ok(())
+    });
+}
+fn register_natproc_memory(native_procedures: &mut NativeProceduresMap) {
+    register_native_procedure!(native_procedures, Alloc, |context| {
+        let to_alloc = peek(context.stack, 0);
+
+        let handle = context
+            .allocation_array
+            .alloc(to_alloc, Some(context.position.clone()))?;
+
+        context.stack.push_back(handle);
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Free, |context| {
+        let alloc_addr = peek(context.stack, 0);
+        context.allocation_array.free(alloc_addr)?;
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Read, |context| {
+        let idx = peek(context.stack, 0);
+        let addr = peek(context.stack, 1);
+
+        context
+            .stack
+            .push_back(context.allocation_array.read(addr, idx)?);
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Write, |context| {
+        let value = peek(context.stack, 0);
+        let idx = peek(context.stack, 1);
+        let addr = peek(context.stack, 2);
+
+        context.allocation_array.write(addr, idx, value)?;
+        Ok(())
+    });
+}
+fn register_natproc_memory_ext(native_procedures: &mut NativeProceduresMap) {
+    register_native_procedure!(native_procedures, Length, |context| {
+        let addr = peek(context.stack, 0);
+
+        context
+            .stack
+            .push_back(context.allocation_array.len(addr)? as u64);
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Realloc, |context| {
+        let size = peek(context.stack, 0);
+        let addr = peek(context.stack, 1);
+
+        context.allocation_array.resize(addr, size)?;
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Copy, |context| {
+        let count = peek(context.stack, 0);
+        let src_idx = peek(context.stack, 1);
+        let src_addr = peek(context.stack, 2);
+        let dst_idx = peek(context.stack, 3);
+        let dst_addr = peek(context.stack, 4);
+
+        context
+            .allocation_array
+            .copy(dst_addr, dst_idx, src_addr, src_idx, count)?;
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Fill, |context| {
+        let value = peek(context.stack, 0);
+        let count = peek(context.stack, 1);
+        let idx = peek(context.stack, 2);
+        let addr = peek(context.stack, 3);
+
+        context.allocation_array.fill(addr, idx, count, value)?;
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, Compare, |context| {
+        let count = peek(context.stack, 0);
+        let second_idx = peek(context.stack, 1);
+        let second_addr = peek(context.stack, 2);
+        let first_idx = peek(context.stack, 3);
+        let first_addr = peek(context.stack, 4);
+
+        let ordering = context.allocation_array.compare(
+            first_addr,
+            first_idx,
+            second_addr,
+            second_idx,
+            count,
+        )?;
+        context.stack.push_back(ordering as i64 as u64);
+        Ok(())
+    });
+}
+// Reads a null-terminated string stored in a memory block, 8 bytes per value.
+// Characters may cross value boundaries, so the bytes are decoded together.
+fn read_string(allocation_array: &AllocationArray, addr: u64) -> Result<String, NativeError> {
+    let mut bytes = Vec::new();
+
+    for block in allocation_array.get(addr)? {
+        bytes.extend_from_slice(&block.to_ne_bytes());
+    }
+
+    if let Some(end) = bytes.iter().position(|&byte| byte == 0) {
+        bytes.truncate(end);
+    }
+
+    String::from_utf8(bytes).map_err(|_| NativeError::InvalidString(addr))
+}
+
+fn register_natproc_strings(native_procedures: &mut NativeProceduresMap) {
+    register_native_procedure!(native_procedures, Print, |context| {
+        let addr = peek(context.stack, 0);
+        let string = read_string(context.allocation_array, addr)?;
+
+        write_output(context.io, format_args!("{}", string))
+    });
+}
+
+fn assertion_failed(
+    context: &NativeContext,
+    message_addr: u64,
+    reason: String,
+) -> Result<(), NativeError> {
+    if message_addr == 0 {
+        return Err(NativeError::AssertionFailed(reason));
+    }
+
+    let message = read_string(context.allocation_array, message_addr)?;
+    Err(NativeError::AssertionFailed(format!(
+        "{}: {}",
+        message, reason
+    )))
+}
+
+fn register_natproc_assert(native_procedures: &mut NativeProceduresMap) {
+    register_native_procedure!(native_procedures, AssertTrue, |context| {
+        let message_addr = peek(context.stack, 0);
+        let value = peek(context.stack, 1);
+
+        if value == 0 {
+            return assertion_failed(context, message_addr, "expected a true value".to_owned());
+        }
+
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, AssertEqualC, |context| {
+        let message_addr = peek(context.stack, 0);
+        let expected = peek(context.stack, 1) as i64;
+        let actual = peek(context.stack, 2) as i64;
+
+        if actual != expected {
+            return assertion_failed(
+                context,
+                message_addr,
+                format!("expected {}, got {}", expected, actual),
+            );
+        }
+
+        Ok(())
+    });
+
+    register_native_procedure!(native_procedures, AssertEqualZ, |context| {
+        let message_addr = peek(context.stack, 0);
+        let expected = f64::from_bits(peek(context.stack, 1));
+        let actual = f64::from_bits(peek(context.stack, 2));
+
+        if actual != expected && !(actual.is_nan() && expected.is_nan()) {
+            return assertion_failed(
+                context,
+                message_addr,
+                format!("expected {}, got {}", expected, actual),
+            );
+        }
+
+        Ok(())
+    });
+}
+
+pub fn register_standard(native_procedures: &mut NativeProceduresMap) {
+    register_natproc_io(native_procedures);
+    register_natproc_memory(native_procedures);
+    register_natproc_memory_ext(native_procedures);
+    register_natproc_strings(native_procedures);
+    register_natproc_assert(native_procedures);
+}
@@ -56,15 +56,150 @@ pub enum Instruction {
     Wroc,
     Stop,
 
+    // Indirect calls and jumps (extension POŚREDNIE)
+    WywolajS,
+    IdzDoS,
+
+    // Stack manipulation (extension STOS)
+    Powiel,
+    Zamien,
+    Nad,
+    Obroc,
+    Wybierz(u64),
+
     // Interpreter communication
     Nat(u64),
     BrakOperacji,
 }
 
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            // Stack
+            Instruction::Pchnij(_) => "PCHNIJ",
+            Instruction::Usun => "USUŃ",
+            Instruction::ZmiennaK(_) => "ZMIENNA.K",
+            Instruction::ZmiennaU(_) => "ZMIENNA.U",
+
+            // Arithemtics
+            Instruction::DodajC => "DODAJ.C",
+            Instruction::DodajZ => "DODAJ.Z",
+
+            Instruction::OdejmC => "ODEJM.C",
+            Instruction::OdejmZ => "ODEJM.Z",
+
+            Instruction::MnozC => "MNÓŻ.C",
+            Instruction::MnozZ => "MNÓŻ.Z",
+
+            Instruction::DzielC => "DZIEL.C",
+            Instruction::DzielZ => "DZIEL.Z",
+
+            Instruction::ResztaC => "RESZTA.C",
+            Instruction::ResztaZ => "RESZTA.Z",
+
+            Instruction::JakoCZ => "JAKO.CZ",
+            Instruction::JakoZC => "JAKO.ZC",
+
+            // Comparisons
+            Instruction::NieL => "NIE.L",
+            Instruction::Rowne => "RÓWNE",
+            Instruction::RowneZ => "RÓWNE.Z",
+
+            Instruction::MniejC => "MNIEJ.C",
+            Instruction::MniejZ => "MNIEJ.Z",
+
+            Instruction::MNrowC => "MNRÓW.C",
+            Instruction::MNrowZ => "MNRÓW.Z",
+
+            // Bitwise operations
+            Instruction::NieB => "NIE.B",
+            Instruction::I => "I",
+            Instruction::Lub => "LUB",
+            Instruction::XLub => "XLUB",
+            Instruction::PrzesunL => "PRZESUŃ.L",
+            Instruction::PrzesunR => "PRZESUŃ.P",
+
+            // PC register manipulation
+            Instruction::IdzDo(_) => "IDŹDO",
+            Instruction::IdzDoZe(_) => "IDŹDO.ZE",
+            Instruction::IdzDoNz(_) => "IDŹDO.NZ",
+            Instruction::Wywolaj(_) => "WYWOŁAJ",
+            Instruction::Wroc => "WRÓĆ",
+            Instruction::Stop => "STOP",
+
+            // Indirect calls and jumps
+            Instruction::WywolajS => "WYWOŁAJ.S",
+            Instruction::IdzDoS => "IDŹDO.S",
+
+            // Stack manipulation
+            Instruction::Powiel => "POWIEL",
+            Instruction::Zamien => "ZAMIEŃ",
+            Instruction::Nad => "NAD",
+            Instruction::Obroc => "OBRÓĆ",
+            Instruction::Wybierz(_) => "WYBIERZ",
+
+            // Interpreter communication
+            Instruction::Nat(_) => "NAT",
+            Instruction::BrakOperacji => "BRAK.OPERACJI",
+        }
+    }
+
+    pub fn operand(&self) -> Option<u64> {
+        match *self {
+            Instruction::Pchnij(operand)
+            | Instruction::ZmiennaK(operand)
+            | Instruction::ZmiennaU(operand)
+            | Instruction::IdzDo(operand)
+            | Instruction::IdzDoZe(operand)
+            | Instruction::IdzDoNz(operand)
+            | Instruction::Wywolaj(operand)
+            | Instruction::Wybierz(operand)
+            | Instruction::Nat(operand) => Some(operand),
+            _ => None,
+        }
+    }
+
+    // The extension the instruction belongs to, None for CVM 1 instructions
+    pub fn extension(&self) -> Option<Extension> {
+        match self {
+            Instruction::WywolajS | Instruction::IdzDoS => Some(Extension::Indirect),
+            Instruction::Powiel
+            | Instruction::Zamien
+            | Instruction::Nad
+            | Instruction::Obroc
+            | Instruction::Wybierz(_) => Some(Extension::Stack),
+            _ => None,
+        }
+    }
+}
+
+// Extensions of CVM 1 a file has to declare with @Rozszerzenie before using
+// their instructions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Extension {
+    // WYWOŁAJ.S and IDŹDO.S, calls and jumps to a target popped from the stack
+    Indirect,
+    // POWIEL, ZAMIEŃ, NAD, OBRÓĆ and WYBIERZ, shuffling the top of the stack
+    Stack,
+}
+
+impl Extension {
+    pub fn from_name(name: &str) -> Option<Extension> {
+        match name {
+            "POŚREDNIE" => Some(Extension::Indirect),
+            "STOS" => Some(Extension::Stack),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Directive {
     CVMAVersion(u64),
     Procedure(u64, String, u64),
+    // Marks the next procedure as a test
+    Test,
+    Extension(Extension),
     Invalid,
 }
 
@@ -74,7 +209,11 @@ pub struct Procedure {
     pub index: u64,
     pub name: String,
     pub parameter_count: u64,
+    // Declared with @Test, run by the test runner
+    pub is_test: bool,
     pub code: VecDeque<Instruction>,
+    // Source position of every instruction in `code`
+    pub positions: VecDeque<error::Position>,
 }
 
 #[derive(Clone, Debug)]
@@ -87,6 +226,9 @@ pub enum ParseErrorKind {
 
     InstructionOutsideOfProcedure,
     DirectiveNotEnoughParameters,
+
+    ExtensionUnknown,
+    ExtensionNotDeclared(Extension),
 }
 
 impl error::Info for error::Error<ParseErrorKind> {
@@ -102,6 +244,10 @@ impl error::Info for error::Error<ParseErrorKind> {
             ParseErrorKind::DirectiveNotEnoughParameters => {
                 "directive requires more parameters than inputed"
             }
+            ParseErrorKind::ExtensionUnknown => "this extension is unknown",
+            ParseErrorKind::ExtensionNotDeclared(_) => {
+                "instruction belongs to an extension that is not declared"
+            }
         }
     }
 
@@ -113,12 +259,20 @@ impl error::Info for error::Error<ParseErrorKind> {
             | ParseErrorKind::DirectiveUnknown => "look at the spec maybe you got something wrong",
             ParseErrorKind::InstructionOutsideOfProcedure => "place it inside the procedure",
             ParseErrorKind::DirectiveNotEnoughParameters => "input required parameters",
+            ParseErrorKind::ExtensionUnknown => "look at the README for supported extensions",
+            ParseErrorKind::ExtensionNotDeclared(Extension::Indirect) => {
+                "declare it with @Rozszerzenie POŚREDNIE before using it"
+            }
+            ParseErrorKind::ExtensionNotDeclared(Extension::Stack) => {
+                "declare it with @Rozszerzenie STOS before using it"
+            }
         }
     }
 }
 // Parsed CVMA file
 pub struct CVMAFile {
     pub language_version: u64,
+    pub extensions: Vec<Extension>,
     pub procedures: VecDeque<Procedure>,
     pub errors: VecDeque<error::Error<ParseErrorKind>>,
 }
@@ -148,13 +302,11 @@ pub fn get_number_from_string(
         use_dec = false;
     }
 
-    let result;
-
-    if use_dec {
-        result = string.parse::<u64>();
+    let result = if use_dec {
+        string.parse::<u64>()
     } else {
-        result = u64::from_str_radix(string, 16);
-    }
+        u64::from_str_radix(string, 16)
+    };
 
     match result {
         Ok(number) => number,
@@ -169,14 +321,14 @@ pub fn get_number_from_string(
 }
 
 pub fn get_instruction_from_strings(
-    instruction: &String,
-    parameters: &String,
+    instruction: &str,
+    parameters: &str,
     position: &mut error::Position,
     errors: &mut VecDeque<error::Error<ParseErrorKind>>,
 ) -> Instruction {
     position.column = (instruction.len() + 1) as i32;
 
-    match instruction.as_str() {
+    match instruction {
         // Stack
         "PCHNIJ" => {
             Instruction::Pchnij(get_number_from_string(parameters, false, position, errors))
@@ -225,7 +377,9 @@ pub fn get_instruction_from_strings(
         "LUB" => Instruction::Lub,
         "XLUB" => Instruction::XLub,
         "PRZESUŃ.L" => Instruction::PrzesunL,
-        "PRZESUŃ.R" => Instruction::PrzesunR,
+        // PRZESUŃ.P is the spelling used by the spec, PRZESUŃ.R is kept for
+        // existing programs
+        "PRZESUŃ.P" | "PRZESUŃ.R" => Instruction::PrzesunR,
 
         // PC register manipulation
         "IDŹDO" => Instruction::IdzDo(get_number_from_string(parameters, false, position, errors)),
@@ -241,6 +395,19 @@ pub fn get_instruction_from_strings(
         "STOP" => Instruction::Stop,
         "WRÓĆ" => Instruction::Wroc,
 
+        // Indirect calls and jumps
+        "WYWOŁAJ.S" => Instruction::WywolajS,
+        "IDŹDO.S" => Instruction::IdzDoS,
+
+        // Stack manipulation
+        "POWIEL" => Instruction::Powiel,
+        "ZAMIEŃ" => Instruction::Zamien,
+        "NAD" => Instruction::Nad,
+        "OBRÓĆ" => Instruction::Obroc,
+        "WYBIERZ" => {
+            Instruction::Wybierz(get_number_from_string(parameters, true, position, errors))
+        }
+
         // Interpreter communication
         "NAT" => Instruction::Nat(get_number_from_string(parameters, false, position, errors)),
 
@@ -256,14 +423,14 @@ pub fn get_instruction_from_strings(
 }
 
 pub fn get_directive_from_strings(
-    directive: &String,
-    parameters: &String,
+    directive: &str,
+    parameters: &str,
     position: &mut error::Position,
     errors: &mut VecDeque<error::Error<ParseErrorKind>>,
 ) -> Directive {
-    match directive.as_str() {
+    match directive {
         "@CVMA" => {
-            let version = get_number_from_string(parameters.as_str(), true, position, errors);
+            let version = get_number_from_string(parameters, true, position, errors);
             Directive::CVMAVersion(version)
         }
         "@Procedura" => {
@@ -284,6 +451,29 @@ pub fn get_directive_from_strings(
                 get_number_from_string(params[2], false, position, errors),
             )
         }
+        "@Test" => Directive::Test,
+        "@Rozszerzenie" => {
+            if parameters.is_empty() {
+                errors.push_back(error::Error {
+                    position: position.clone(),
+                    kind: ParseErrorKind::DirectiveNotEnoughParameters,
+                });
+
+                return Directive::Invalid;
+            }
+
+            match Extension::from_name(parameters) {
+                Some(extension) => Directive::Extension(extension),
+                None => {
+                    errors.push_back(error::Error {
+                        position: position.clone(),
+                        kind: ParseErrorKind::ExtensionUnknown,
+                    });
+
+                    Directive::Invalid
+                }
+            }
+        }
         _ => {
             errors.push_back(error::Error {
                 position: position.clone(),
@@ -302,6 +492,7 @@ pub fn read_from_string(content: &str) -> CVMAFile {
 
     let mut cvma_file = CVMAFile {
         language_version: 0,
+        extensions: Vec::new(),
         procedures: VecDeque::new(),
         errors: VecDeque::new(),
     };
@@ -310,7 +501,9 @@ pub fn read_from_string(content: &str) -> CVMAFile {
         index: 0,
         name: String::new(),
         parameter_count: 0,
+        is_test: false,
         code: VecDeque::new(),
+        positions: VecDeque::new(),
     };
 
     let mut parse_position = error::Position { line: 0, column: 0 };
@@ -389,7 +582,7 @@ pub fn read_from_string(content: &str) -> CVMAFile {
                     continue;
                 }
 
-                parse_position.line = (line + 1) as i32;
+                parse_position.line = line + 1;
 
                 if instruction.starts_with("@") {
                     match get_directive_from_strings(
@@ -406,6 +599,12 @@ pub fn read_from_string(content: &str) -> CVMAFile {
 
                             is_in_procedure = true;
                         }
+                        Directive::Test => procedure.is_test = true,
+                        Directive::Extension(extension) => {
+                            if !cvma_file.extensions.contains(&extension) {
+                                cvma_file.extensions.push(extension);
+                            }
+                        }
                         Directive::Invalid => {}
                     }
                 } else {
@@ -425,20 +624,34 @@ pub fn read_from_string(content: &str) -> CVMAFile {
                         &mut cvma_file.errors,
                     );
 
-                    procedure.code.push_back(to_push);
+                    if let Some(extension) = to_push.extension() {
+                        if !cvma_file.extensions.contains(&extension) {
+                            parse_position.column = 0;
 
-                    match to_push {
-                        Instruction::Wroc => {
-                            is_in_procedure = false;
-
-                            cvma_file.procedures.push_back(procedure.clone());
-
-                            procedure.index = 0;
-                            procedure.name = String::new();
-                            procedure.parameter_count = 0;
-                            procedure.code.clear();
+                            cvma_file.errors.push_back(error::Error {
+                                position: parse_position.clone(),
+                                kind: ParseErrorKind::ExtensionNotDeclared(extension),
+                            });
                         }
-                        _ => {}
+                    }
+
+                    procedure.code.push_back(to_push);
+                    procedure.positions.push_back(error::Position {
+                        line: parse_position.line,
+                        column: 0,
+                    });
+
+                    if let Instruction::Wroc = to_push {
+                        is_in_procedure = false;
+
+                        cvma_file.procedures.push_back(procedure.clone());
+
+                        procedure.index = 0;
+                        procedure.name = String::new();
+                        procedure.parameter_count = 0;
+                        procedure.is_test = false;
+                        procedure.code.clear();
+                        procedure.positions.clear();
                     }
                 }
 
@@ -0,0 +1,316 @@
+use crate::natives::{NativeContext, NativeError, NativeProcedureFn};
+use std::io::{self, Write};
+
+// A call of a nondeterministic native procedure: the values it pushed, or the
+// message of the error it failed with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedCall {
+    pub native: u64,
+    pub result: Result<Vec<u64>, String>,
+}
+
+// Results of nondeterministic native procedures, like the ones reading input,
+// are either written to a log or read back from one instead of calling the
+// native procedures. Other effects of the native procedures are not recorded.
+pub enum Recording {
+    Record(Box<dyn Write>),
+    // `position` is the index of the call replayed next
+    Replay {
+        calls: Vec<RecordedCall>,
+        position: usize,
+    },
+    // Keeps the calls in memory. Calls before `position` are replayed, so the
+    // VM can go back to a checkpoint and run again without reading input twice,
+    // later ones are made and kept.
+    Rewindable {
+        calls: Vec<RecordedCall>,
+        position: usize,
+    },
+}
+
+// Writes a call as a line of the log:
+//   NAT 3 41                        ; native procedure, pushed values (hex)
+//   NAT 4 ! reached the end of input
+pub fn write_call(sink: &mut dyn Write, call: &RecordedCall) -> io::Result<()> {
+    write!(sink, "NAT {:X}", call.native)?;
+
+    match &call.result {
+        Ok(values) => {
+            for value in values {
+                write!(sink, " {:X}", value)?;
+            }
+        }
+        Err(message) => write!(sink, " ! {}", message)?,
+    }
+
+    writeln!(sink)?;
+    sink.flush()
+}
+
+// Reads a log written while recording.
+pub fn read_from_string(content: &str) -> Result<Vec<RecordedCall>, String> {
+    let mut calls = Vec::new();
+
+    for (line_idx, line) in content.lines().enumerate() {
+        let invalid_line = || format!("line {}: invalid recorded call", line_idx + 1);
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (call, error) = match line.split_once(" ! ") {
+            Some((call, error)) => (call, Some(error)),
+            None => (line, None),
+        };
+
+        let mut parts = call.split_whitespace();
+        if parts.next() != Some("NAT") {
+            return Err(invalid_line());
+        }
+
+        let native = parts
+            .next()
+            .and_then(|index| u64::from_str_radix(index, 16).ok())
+            .ok_or_else(invalid_line)?;
+
+        let values = parts
+            .map(|value| u64::from_str_radix(value, 16).map_err(|_| invalid_line()))
+            .collect::<Result<Vec<u64>, String>>()?;
+
+        let result = match error {
+            Some(_) if !values.is_empty() => return Err(invalid_line()),
+            Some(error) => Err(error.to_owned()),
+            None => Ok(values),
+        };
+
+        calls.push(RecordedCall { native, result });
+    }
+
+    Ok(calls)
+}
+
+impl Recording {
+    // Calls a nondeterministic native procedure while recording, or takes its
+    // result from the log while replaying.
+    pub(crate) fn call(
+        &mut self,
+        native: u64,
+        function: &NativeProcedureFn,
+        context: &mut NativeContext,
+    ) -> Result<(), NativeError> {
+        match self {
+            Recording::Record(sink) => {
+                let (result, call) = record_call(native, function, context);
+
+                // Like the trace, the log does not stop the program if it fails.
+                // A call waiting for input is made again later.
+                if !matches!(result, Err(NativeError::InputNotReady)) {
+                    let _ = write_call(sink, &call);
+                }
+
+                result
+            }
+            Recording::Replay { calls, position } => match calls.get(*position) {
+                Some(call) => {
+                    *position += 1;
+                    replay_call(native, call, context)
+                }
+                None => Err(NativeError::ReplayDiverged(
+                    "the recording has no more calls".to_owned(),
+                )),
+            },
+            Recording::Rewindable { calls, position } => {
+                *position += 1;
+
+                match calls.get(*position - 1) {
+                    Some(call) => replay_call(native, call, context),
+                    None => {
+                        let (result, call) = record_call(native, function, context);
+
+                        if let Err(NativeError::InputNotReady) = result {
+                            *position -= 1;
+                        } else {
+                            calls.push(call);
+                        }
+
+                        result
+                    }
+                }
+            }
+        }
+    }
+
+    // Index of the call made next, None when the calls are not kept.
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            Recording::Record(_) => None,
+            Recording::Replay { position, .. } | Recording::Rewindable { position, .. } => {
+                Some(*position)
+            }
+        }
+    }
+
+    pub fn set_position(&mut self, new_position: usize) {
+        match self {
+            Recording::Record(_) => {}
+            Recording::Replay { position, .. } | Recording::Rewindable { position, .. } => {
+                *position = new_position
+            }
+        }
+    }
+}
+
+fn record_call(
+    native: u64,
+    function: &NativeProcedureFn,
+    context: &mut NativeContext,
+) -> (Result<(), NativeError>, RecordedCall) {
+    let length = context.stack.len();
+    let result = function(context);
+
+    let call = RecordedCall {
+        native,
+        result: match &result {
+            Ok(()) => Ok(context.stack.iter().skip(length).copied().collect()),
+            Err(error) => Err(error.to_string()),
+        },
+    };
+
+    (result, call)
+}
+
+fn replay_call(
+    native: u64,
+    call: &RecordedCall,
+    context: &mut NativeContext,
+) -> Result<(), NativeError> {
+    if call.native != native {
+        return Err(NativeError::ReplayDiverged(format!(
+            "the recording calls NAT {:X} here",
+            call.native
+        )));
+    }
+
+    match &call.result {
+        Ok(values) => {
+            context.stack.extend(values);
+            Ok(())
+        }
+        Err(message) => Err(NativeError::Replayed(message.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+
+    fn replay(program: &str, recording: &str) -> Vm {
+        let cvma_file = crate::parse::read_from_string(program);
+        assert!(cvma_file.errors.is_empty());
+
+        let calls = read_from_string(recording).unwrap();
+        Vm::builder(cvma_file.procedures)
+            .recording(Recording::Replay { calls, position: 0 })
+            .build()
+    }
+
+    const TWO_READS: &str = "@CVMA 1
+@Procedura 0 \"main\" 0
+NAT 3
+NAT 3
+STOP
+WRÓĆ
+";
+
+    #[test]
+    fn reads_values_and_errors() {
+        let calls =
+            read_from_string("NAT 3 2A\n\nNAT 4 ! reached the end of input\nNAT 5\n").unwrap();
+
+        assert_eq!(
+            calls,
+            [
+                RecordedCall {
+                    native: 3,
+                    result: Ok(vec![0x2A]),
+                },
+                RecordedCall {
+                    native: 4,
+                    result: Err("reached the end of input".to_owned()),
+                },
+                RecordedCall {
+                    native: 5,
+                    result: Ok(vec![]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let calls = vec![
+            RecordedCall {
+                native: 0x1F,
+                result: Ok(vec![1, u64::MAX]),
+            },
+            RecordedCall {
+                native: 3,
+                result: Err("got invalid input, expected u64".to_owned()),
+            },
+        ];
+
+        let mut log = Vec::new();
+        for call in &calls {
+            write_call(&mut log, call).unwrap();
+        }
+
+        assert_eq!(
+            read_from_string(&String::from_utf8(log).unwrap()).unwrap(),
+            calls
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "NAT",
+            "NAT x",
+            "NAT 3 zz",
+            "GET 3 1",
+            "NAT 3 1 ! pushed and failed",
+        ] {
+            assert_eq!(
+                read_from_string(&format!("NAT 3 1\n{}\n", line)),
+                Err("line 2: invalid recorded call".to_owned()),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_recording_stops_the_replay() {
+        let mut vm = replay(TWO_READS, "NAT 3 7\n");
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.position.line, 4);
+        assert_eq!(
+            error.kind.get_message(),
+            "native procedure GetC (3): replay diverged, the recording has no more calls"
+        );
+        assert_eq!(vm.stack, [7]);
+    }
+
+    #[test]
+    fn other_native_in_the_recording_stops_the_replay() {
+        let mut vm = replay(TWO_READS, "NAT 3 7\nNAT 4 0\n");
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.position.line, 4);
+        assert_eq!(
+            error.kind.get_message(),
+            "native procedure GetC (3): replay diverged, the recording calls NAT 4 here"
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use crate::error;
+use crate::memory::{HeapStatistics, Slot};
+use crate::parse::Procedure;
+use crate::vm::{CallFrame, Vm};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+// A snapshot is the state of a running VM as text, one item per line:
+//   KREMOWKUJ SNAPSHOT 1
+//   program 8C1F03A9D1B2E4F7      ; program hash, see `program_hash`
+//   fuel 1F4                      ; fuel consumed so far
+//   frame 0 5 0                   ; procedure index, pc, Bottom, outermost first
+//   stack 1 2 3                   ; the value stack, bottom first
+//   heap 3 2 A                    ; allocations, peak live blocks and words
+//   slot 1 4:0 0 0 0              ; generation, allocation site, values
+//   slot 2 free                   ; generation of a slot without a block
+//   free 1                        ; free slots in the order they are reused
+// All numbers are hex. Input, output, limits and the remaining fuel are not
+// part of the state, they come from the VM the snapshot is resumed in.
+const HEADER: &str = "KREMOWKUJ SNAPSHOT 1";
+
+pub const FAILED: &str = "the program failed with a runtime error and cannot be resumed";
+
+// FNV-1a hash of the code of the procedures. Names and source positions are
+// left out, so only changes to what the program does make a snapshot unusable.
+pub fn program_hash(procedures: &VecDeque<Procedure>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut add = |text: String| {
+        for byte in text.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+
+    for procedure in procedures {
+        add(format!(
+            "@Procedura {} {}\n",
+            procedure.index, procedure.parameter_count
+        ));
+
+        for instruction in &procedure.code {
+            add(format!(
+                "{} {:?}\n",
+                instruction.mnemonic(),
+                instruction.operand()
+            ));
+        }
+    }
+
+    hash
+}
+
+fn write_values(output: &mut dyn Write, values: &[u64]) -> io::Result<()> {
+    for value in values {
+        write!(output, " {:X}", value)?;
+    }
+
+    writeln!(output)
+}
+
+// A program that failed with a runtime error is not written, resuming it
+// would skip the failing instruction.
+pub fn write(vm: &Vm, output: &mut dyn Write) -> io::Result<()> {
+    if vm.error().is_some() {
+        return Err(io::Error::other(FAILED));
+    }
+
+    let procedures = vm.procedures();
+    let (slots, free_slots) = vm.allocation_array.slots();
+    let statistics = vm.allocation_array.statistics();
+
+    writeln!(output, "{}", HEADER)?;
+    writeln!(output, "program {:X}", program_hash(procedures))?;
+    writeln!(output, "fuel {:X}", vm.fuel_consumed())?;
+
+    for frame in vm.call_stack() {
+        writeln!(
+            output,
+            "frame {:X} {:X} {:X}",
+            procedures[frame.procedure].index, frame.pc, frame.bottom
+        )?;
+    }
+
+    write!(output, "stack")?;
+    let stack: Vec<u64> = vm.stack.iter().copied().collect();
+    write_values(output, &stack)?;
+
+    writeln!(
+        output,
+        "heap {:X} {:X} {:X}",
+        statistics.allocation_count, statistics.peak_live_blocks, statistics.peak_live_words
+    )?;
+
+    for slot in slots {
+        write!(output, "slot {:X}", slot.generation)?;
+
+        match (&slot.block, &slot.site) {
+            (None, _) => writeln!(output, " free")?,
+            (Some(block), site) => {
+                match site {
+                    Some(site) => write!(output, " {:X}:{:X}", site.line, site.column)?,
+                    None => write!(output, " -")?,
+                }
+
+                write_values(output, block)?;
+            }
+        }
+    }
+
+    write!(output, "free")?;
+    let free_slots: Vec<u64> = free_slots.iter().map(|&slot| slot as u64).collect();
+    write_values(output, &free_slots)
+}
+
+fn parse_hex(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text, 16).map_err(|_| format!("{} is not a hex number", text))
+}
+
+// Restores the state written by `write` into a VM built from the same program.
+pub fn read(vm: &mut Vm, content: &str) -> Result<(), String> {
+    let mut lines = content.lines();
+
+    if lines.next() != Some(HEADER) {
+        return Err("not a snapshot".to_owned());
+    }
+
+    let mut checkpoint = vm.checkpoint();
+    checkpoint.frames.clear();
+    checkpoint.stack.clear();
+    checkpoint.recording_position = None;
+    checkpoint.error = None;
+
+    let mut program = None;
+    let mut slots = Vec::new();
+    let mut free_slots = Vec::new();
+    let mut statistics = HeapStatistics::default();
+
+    for (line_idx, line) in lines.enumerate() {
+        let mut parts = line.split_whitespace();
+        let at_line = |error: String| format!("line {}: {}", line_idx + 2, error);
+
+        let item = match parts.next() {
+            Some(item) => item,
+            None => continue,
+        };
+        let parts: Vec<&str> = parts.collect();
+
+        match (item, parts.as_slice()) {
+            ("program", [hash]) => program = Some(parse_hex(hash).map_err(at_line)?),
+            ("fuel", [consumed]) => {
+                checkpoint.fuel_consumed = parse_hex(consumed).map_err(at_line)?
+            }
+            ("frame", [index, pc, bottom]) => {
+                let index = parse_hex(index).map_err(at_line)?;
+                let procedure = vm
+                    .procedures()
+                    .iter()
+                    .position(|procedure| procedure.index == index)
+                    .ok_or_else(|| at_line(format!("procedure {:X} is not defined", index)))?;
+
+                checkpoint.frames.push(CallFrame {
+                    procedure,
+                    pc: parse_hex(pc).map_err(at_line)?,
+                    bottom: parse_hex(bottom).map_err(at_line)?,
+                });
+            }
+            ("stack", values) => {
+                for value in values {
+                    checkpoint
+                        .stack
+                        .push_back(parse_hex(value).map_err(at_line)?);
+                }
+            }
+            ("heap", [allocations, peak_blocks, peak_words]) => {
+                statistics.allocation_count = parse_hex(allocations).map_err(at_line)?;
+                statistics.peak_live_blocks = parse_hex(peak_blocks).map_err(at_line)? as usize;
+                statistics.peak_live_words = parse_hex(peak_words).map_err(at_line)?;
+            }
+            ("slot", [generation, rest @ ..]) => {
+                let generation = parse_hex(generation).map_err(at_line)? as u32;
+
+                let slot = match rest {
+                    ["free"] => Slot {
+                        generation,
+                        block: None,
+                        site: None,
+                    },
+                    [site, values @ ..] => {
+                        let site = match site.split_once(':') {
+                            Some((line, column)) => Some(error::Position {
+                                line: parse_hex(line).map_err(at_line)? as i32,
+                                column: parse_hex(column).map_err(at_line)? as i32,
+                            }),
+                            None if *site == "-" => None,
+                            None => {
+                                return Err(at_line(format!("invalid allocation site {}", site)))
+                            }
+                        };
+
+                        let block = values
+                            .iter()
+                            .map(|value| parse_hex(value))
+                            .collect::<Result<Vec<u64>, String>>()
+                            .map_err(at_line)?;
+
+                        Slot {
+                            generation,
+                            block: Some(block),
+                            site,
+                        }
+                    }
+                    [] => return Err(at_line("invalid slot".to_owned())),
+                };
+
+                slots.push(slot);
+            }
+            ("free", indices) => {
+                for index in indices {
+                    free_slots.push(parse_hex(index).map_err(at_line)? as u32);
+                }
+            }
+            _ => return Err(at_line(format!("invalid snapshot item {}", item))),
+        }
+    }
+
+    match program {
+        Some(hash) if hash == program_hash(vm.procedures()) => {}
+        Some(_) => return Err("the snapshot was taken of a different program".to_owned()),
+        None => return Err("the snapshot has no program hash".to_owned()),
+    }
+
+    for frame in &checkpoint.frames {
+        let procedure = &vm.procedures()[frame.procedure];
+
+        if frame.pc as usize >= procedure.code.len() || frame.bottom > checkpoint.stack.len() as u64
+        {
+            return Err(format!(
+                "the frame of procedure {:X} is outside of the program",
+                procedure.index
+            ));
+        }
+    }
+
+    for &index in &free_slots {
+        if slots
+            .get(index as usize)
+            .is_none_or(|slot| slot.block.is_some())
+        {
+            return Err(format!("free slot {:X} is not an empty slot", index));
+        }
+    }
+
+    checkpoint
+        .allocation_array
+        .restore_slots(slots, free_slots, statistics);
+    vm.restore(&checkpoint);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Status;
+
+    // Allocates a block, writes 5 to it, then reads it back and adds 3
+    const PROGRAM: &str = "@CVMA 1
+@Procedura 0 \"main\" 0
+PCHNIJ 2
+NAT 10
+PCHNIJ 0
+PCHNIJ 5
+NAT 13
+USUŃ
+USUŃ
+PCHNIJ 0
+NAT 12
+PCHNIJ 3
+DODAJ.C
+STOP
+WRÓĆ
+";
+
+    fn build_vm(program: &str, fuel: u64) -> Vm {
+        let cvma_file = crate::parse::read_from_string(program);
+        assert!(cvma_file.errors.is_empty());
+
+        Vm::builder(cvma_file.procedures).fuel(fuel).build()
+    }
+
+    fn take_snapshot(vm: &Vm) -> String {
+        let mut output = Vec::new();
+        write(vm, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn resumed_program_ends_like_an_uninterrupted_one() {
+        let mut uninterrupted = build_vm(PROGRAM, 100);
+        assert!(matches!(uninterrupted.run(), Ok(Status::Halted)));
+
+        let mut vm = build_vm(PROGRAM, 6);
+        assert!(matches!(vm.run(), Ok(Status::OutOfFuel)));
+        let snapshot = take_snapshot(&vm);
+
+        let mut resumed = build_vm(PROGRAM, 100);
+        read(&mut resumed, &snapshot).unwrap();
+
+        assert_eq!(resumed.stack, vm.stack);
+        assert_eq!(resumed.fuel_consumed(), 6);
+        assert_eq!(take_snapshot(&resumed), snapshot);
+
+        assert!(matches!(resumed.resume(), Ok(Status::Halted)));
+        assert_eq!(resumed.stack, uninterrupted.stack);
+        assert_eq!(resumed.stack[3], 8);
+
+        let block = resumed.stack[1];
+        assert_eq!(resumed.allocation_array.read(block, 0).unwrap(), 5);
+        assert_eq!(resumed.fuel_consumed(), uninterrupted.fuel_consumed());
+    }
+
+    #[test]
+    fn failed_program_is_not_written() {
+        let mut vm = build_vm(
+            "@CVMA 1\n@Procedura 0 \"main\" 0\nPCHNIJ 7\nPCHNIJ 0\nDZIEL.C\nSTOP\nWRÓĆ\n",
+            100,
+        );
+        assert!(vm.run().is_err());
+
+        let error = write(&vm, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), FAILED);
+    }
+
+    #[test]
+    fn snapshot_of_another_program_is_rejected() {
+        let mut vm = build_vm(PROGRAM, 6);
+        assert!(matches!(vm.run(), Ok(Status::OutOfFuel)));
+        let snapshot = take_snapshot(&vm);
+
+        let mut other = build_vm(&PROGRAM.replace("PCHNIJ 3", "PCHNIJ 4"), 100);
+        assert_eq!(
+            read(&mut other, &snapshot),
+            Err("the snapshot was taken of a different program".to_owned())
+        );
+    }
+}
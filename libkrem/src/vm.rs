@@ -0,0 +1,1874 @@
+use crate::error;
+use crate::fuel::CostTable;
+use crate::hooks::{Hooks, NoHooks};
+use crate::memory::{AllocationArray, HeapEvent, HeapReport, MemoryError};
+use crate::natives::{self, Io, NativeContext, NativeError, NativeProcedure, NativeProceduresMap};
+use crate::parse::{Instruction, Procedure};
+use crate::replay::Recording;
+use crate::snapshot;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::mem;
+
+#[derive(Clone, Debug)]
+pub enum RuntimeErrorKind {
+    ProcedureNotFound(u64),
+    NativeProcedureNotFound(u64),
+    NativeProcedureFailed(&'static str, u64, NativeError),
+    StackLimitExceeded(usize),
+    CallDepthExceeded(usize),
+    IntegerOverflow(Instruction),
+    DivisionByZero(Instruction),
+    // Fewer values on the stack than the instruction reads
+    StackUnderflow(Instruction),
+    // Target of IDŹDO.S and the length of the procedure
+    JumpOutOfBounds(u64, usize),
+    ProcedureNameNotFound(String),
+    // Procedure index, parameter count, number of arguments given
+    ArgumentCountMismatch(u64, u64, usize),
+    // A procedure called by the host did not return, the status tells why
+    CallNotFinished(u64, Status),
+}
+
+impl RuntimeErrorKind {
+    pub fn get_message(&self) -> String {
+        match self {
+            RuntimeErrorKind::ProcedureNotFound(index) => {
+                format!("procedure with index {:X} does not exist", index)
+            }
+            RuntimeErrorKind::NativeProcedureNotFound(index) => {
+                format!("native procedure {:X} does not exist", index)
+            }
+            RuntimeErrorKind::NativeProcedureFailed(name, index, error) => {
+                format!("native procedure {} ({:X}): {}", name, index, error)
+            }
+            RuntimeErrorKind::StackLimitExceeded(limit) => {
+                format!("value stack grew beyond the limit of {} values", limit)
+            }
+            RuntimeErrorKind::CallDepthExceeded(limit) => {
+                format!("call depth exceeded the limit of {} procedures", limit)
+            }
+            RuntimeErrorKind::IntegerOverflow(instruction) => {
+                format!("integer overflow in {}", instruction.mnemonic())
+            }
+            RuntimeErrorKind::DivisionByZero(instruction) => {
+                format!("division by zero in {}", instruction.mnemonic())
+            }
+            RuntimeErrorKind::StackUnderflow(instruction) => {
+                format!("stack underflow in {}", instruction.mnemonic())
+            }
+            RuntimeErrorKind::JumpOutOfBounds(target, length) => format!(
+                "jump to {:X} is outside of the procedure, which has {} instructions",
+                target, length
+            ),
+            RuntimeErrorKind::ProcedureNameNotFound(name) => {
+                format!("procedure named \"{}\" does not exist", name)
+            }
+            RuntimeErrorKind::ArgumentCountMismatch(index, parameters, arguments) => format!(
+                "procedure {:X} takes {} arguments but {} were given",
+                index, parameters, arguments
+            ),
+            RuntimeErrorKind::CallNotFinished(index, status) => format!(
+                "procedure {:X} did not return: {}",
+                index,
+                match status {
+                    Status::Halted => "the program was stopped",
+                    Status::OutOfFuel => "out of fuel",
+                    Status::WaitingForInput => "waiting for input",
+                    Status::Paused => "paused",
+                }
+            ),
+        }
+    }
+
+    pub fn get_suggestion(&self) -> &'static str {
+        match self {
+            RuntimeErrorKind::ProcedureNotFound(_) => "define the procedure with @Procedura",
+            RuntimeErrorKind::NativeProcedureNotFound(_) => {
+                "look at the spec maybe you got something wrong"
+            }
+            RuntimeErrorKind::StackLimitExceeded(_) => {
+                "remove unused values from the stack or raise the stack limit"
+            }
+            RuntimeErrorKind::CallDepthExceeded(_) => {
+                "check the recursion for a missing base case or raise the call depth limit"
+            }
+            RuntimeErrorKind::IntegerOverflow(_) => {
+                "use smaller values or run without checked arithmetic to wrap around"
+            }
+            RuntimeErrorKind::DivisionByZero(_) => "check the divisor before dividing",
+            RuntimeErrorKind::StackUnderflow(_) => {
+                "push the values the instruction reads before running it"
+            }
+            RuntimeErrorKind::JumpOutOfBounds(..) => "jump to an instruction of the same procedure",
+            RuntimeErrorKind::ProcedureNameNotFound(_) => "define the procedure with @Procedura",
+            RuntimeErrorKind::ArgumentCountMismatch(..) => {
+                "pass as many arguments as the procedure declares in @Procedura"
+            }
+            RuntimeErrorKind::CallNotFinished(..) => {
+                "end the procedure with WRÓĆ instead of STOP, and give it enough fuel and input"
+            }
+            RuntimeErrorKind::NativeProcedureFailed(_, _, error) => match error {
+                NativeError::Memory(MemoryError::InvalidHandle(_)) => {
+                    "pass a memory block returned by Alloc (NAT 10)"
+                }
+                NativeError::Memory(MemoryError::UseAfterFree(_)) => {
+                    "do not use a memory block after freeing it"
+                }
+                NativeError::Memory(MemoryError::DoubleFree(_)) => {
+                    "free every memory block exactly once"
+                }
+                NativeError::Memory(MemoryError::IndexOutOfBounds { .. }) => {
+                    "the index must be smaller than the size of the memory block"
+                }
+                NativeError::Memory(MemoryError::RangeOutOfBounds { .. }) => {
+                    "the range must end within the size of the memory block"
+                }
+                NativeError::Memory(MemoryError::HeapLimitExceeded { .. }) => {
+                    "free unused memory blocks or raise the heap limit"
+                }
+                NativeError::Memory(MemoryError::OutOfMemory(_)) => {
+                    "allocate smaller memory blocks"
+                }
+                NativeError::InvalidInput(_) => "enter a valid number",
+                NativeError::InvalidString(_) => {
+                    "store UTF-8 bytes, 8 per value, and end the string with a 0 byte"
+                }
+                NativeError::InputNotReady => "supply input before resuming the program",
+                NativeError::AssertionFailed(_) => "the tested code does not behave as expected",
+                NativeError::EndOfInput => "provide more input to the program",
+                NativeError::Input(_) => "check that the input is readable UTF-8 text",
+                NativeError::Output(_) => "check that the output can still be written to",
+                NativeError::Replayed(_) => "the recorded run failed here as well",
+                NativeError::ReplayDiverged(_) => {
+                    "replay the recording with the program and flags it was made with"
+                }
+                NativeError::Call(error) => error.kind.get_suggestion(),
+            },
+        }
+    }
+}
+
+pub type RuntimeError = error::Error<RuntimeErrorKind>;
+
+fn runtime_error(
+    procedure: &Procedure,
+    pc: u64,
+    kind: RuntimeErrorKind,
+) -> Result<Status, RuntimeError> {
+    Err(error::Error {
+        position: procedure.positions[pc as usize].clone(),
+        kind,
+    })
+}
+
+// Evaluates to true if the operation overflowed, the wrapped result is pushed
+// either way.
+macro_rules! cvm_arithmetics_u64 {
+    ($stack:expr, $op:expr) => {{
+        let y = $stack.pop_back().unwrap() as i64;
+        let x = $stack.pop_back().unwrap() as i64;
+        let (result, overflowed) = $op(x, y);
+        $stack.push_back(result as u64);
+        overflowed
+    }};
+}
+
+// Integer division rounding toward -inf, as required by the spec.
+// i64::MIN / -1 wraps to i64::MIN and reports an overflow.
+fn floor_div(x: i64, y: i64) -> (i64, bool) {
+    let (quotient, overflowed) = x.overflowing_div(y);
+    let remainder = x.wrapping_rem(y);
+
+    if remainder != 0 && (remainder < 0) != (y < 0) {
+        (quotient - 1, overflowed)
+    } else {
+        (quotient, overflowed)
+    }
+}
+
+// Modulo matching floor_div, so that (x / y) * y + x mod y == x. The result
+// has the sign of y, for positive y it is the Euclidean modulo.
+fn floor_mod(x: i64, y: i64) -> (i64, bool) {
+    let remainder = x.wrapping_rem(y);
+
+    if remainder != 0 && (remainder < 0) != (y < 0) {
+        (remainder + y, false)
+    } else {
+        (remainder, false)
+    }
+}
+
+// Floating point counterpart of floor_mod, x - y * floor(x / y) computed without
+// the rounding error of the division. Any NaN operand, an infinite x or a zero y
+// gives NaN, x mod ±inf is x if x has the sign of y and y otherwise.
+fn float_mod(x: f64, y: f64) -> f64 {
+    let remainder = x % y;
+
+    if remainder != 0.0 && (remainder < 0.0) != (y < 0.0) {
+        remainder + y
+    } else {
+        remainder
+    }
+}
+
+// Conversion for JAKO.ZC, rounds toward -inf like integer division. Values out
+// of range saturate to i64::MIN or i64::MAX (so do -inf and inf), NaN becomes 0.
+fn float_to_int(x: f64) -> i64 {
+    x.floor() as i64
+}
+
+// Shifts are logical. Shifting by 64 or more moves every bit out and gives 0
+// instead of masking the shift amount.
+fn shift_left(x: u64, y: u64) -> u64 {
+    if y < 64 {
+        x << y
+    } else {
+        0
+    }
+}
+
+fn shift_right(x: u64, y: u64) -> u64 {
+    if y < 64 {
+        x >> y
+    } else {
+        0
+    }
+}
+
+macro_rules! cvm_arithmetics_f64 {
+    ($stack:expr, $op:tt) => {
+        let y = f64::from_bits($stack.pop_back().unwrap());
+        let x = f64::from_bits($stack.pop_back().unwrap());
+        $stack.push_back((x $op y).to_bits());
+        ()
+    };
+}
+
+// Saved state of a procedure call, as described by the spec
+#[derive(Clone, Debug)]
+pub struct CallFrame {
+    // Position of the procedure in the procedure list, not its CVM index
+    pub procedure: usize,
+    pub pc: u64,
+    pub bottom: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub max_heap_words: Option<u64>,
+    pub max_stack_length: Option<usize>,
+    pub max_call_depth: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    // The program reached STOP or returned from the main procedure
+    Halted,
+    // The next instruction costs more fuel than is left, execution can be
+    // resumed after adding fuel
+    OutOfFuel,
+    // The given number of instructions was executed, the program has not
+    // finished yet
+    Paused,
+    // An input native procedure has no input to read. The NAT runs again on
+    // resume, after the host supplied input.
+    WaitingForInput,
+}
+
+// Execution trace, one line per executed instruction, written before the
+// instruction runs:
+//   PROCEDURE:PC MNEMONIC OPERAND [DEPTH] VALUES
+// PROCEDURE is the CVM index of the procedure, PC the address of the
+// instruction and OPERAND is - for instructions without one. DEPTH is the
+// length of the value stack and VALUES are at most `stack_values` values from
+// its top, the topmost last. All numbers except DEPTH are hex, for example:
+//   0:3 DODAJ.C - [2] 41 3
+pub struct Trace {
+    pub sink: Box<dyn Write>,
+    pub stack_values: usize,
+}
+
+impl Hooks for Trace {
+    fn before_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        write_trace(self, procedure, pc, stack);
+    }
+}
+
+fn write_trace(trace: &mut Trace, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+    let instruction = &procedure.code[pc as usize];
+    let mut line = format!("{:X}:{:X} {} ", procedure.index, pc, instruction.mnemonic());
+
+    match instruction.operand() {
+        Some(operand) => line.push_str(&format!("{:X}", operand)),
+        None => line.push('-'),
+    }
+
+    line.push_str(&format!(" [{}]", stack.len()));
+
+    for value in stack
+        .iter()
+        .skip(stack.len().saturating_sub(trace.stack_values))
+    {
+        line.push_str(&format!(" {:X}", value));
+    }
+
+    // The trace is a diagnostic, failing to write it does not stop the program
+    let _ = writeln!(trace.sink, "{}", line);
+}
+
+// Error outside of the program, caused by how the host used the VM
+fn host_error(kind: RuntimeErrorKind) -> RuntimeError {
+    error::Error {
+        position: error::Position { line: 0, column: 0 },
+        kind,
+    }
+}
+
+// Tells whether the procedure a host call entered returned with WRÓĆ, STOP
+// ends execution without returning
+struct ReturnWatcher {
+    // Procedures entered minus procedures returned from, below the entered one
+    depth: i64,
+}
+
+impl Hooks for ReturnWatcher {
+    fn call(&mut self, _caller: &Procedure, _pc: u64, _callee: &Procedure) {
+        self.depth += 1;
+    }
+
+    fn ret(&mut self, _procedure: &Procedure) {
+        self.depth -= 1;
+    }
+}
+
+// Copy of the execution state the VM can go back to. The procedures, native
+// procedures, I/O and the trace are not part of it. Results of nondeterministic
+// native procedures are only replayed after going back if they are kept by a
+// Replay or Rewindable recording.
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) stack: VecDeque<u64>,
+    pub(crate) allocation_array: AllocationArray,
+    pub(crate) fuel: Option<u64>,
+    pub(crate) fuel_consumed: u64,
+    pub(crate) recording_position: Option<usize>,
+    pub(crate) error: Option<RuntimeError>,
+}
+
+pub struct VmBuilder {
+    procedures: VecDeque<Procedure>,
+    limits: Limits,
+    fuel: Option<u64>,
+    cost_table: CostTable,
+    checked_arithmetic: bool,
+    io: Io,
+    trace: Option<Trace>,
+    recording: Option<Recording>,
+}
+
+impl VmBuilder {
+    pub fn limits(mut self, limits: Limits) -> VmBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn max_heap_words(mut self, words: u64) -> VmBuilder {
+        self.limits.max_heap_words = Some(words);
+        self
+    }
+
+    pub fn max_stack_length(mut self, length: usize) -> VmBuilder {
+        self.limits.max_stack_length = Some(length);
+        self
+    }
+
+    pub fn max_call_depth(mut self, depth: usize) -> VmBuilder {
+        self.limits.max_call_depth = Some(depth);
+        self
+    }
+
+    // Limits the execution to the given amount of fuel, by default every
+    // instruction costs 1.
+    pub fn fuel(mut self, fuel: u64) -> VmBuilder {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn cost_table(mut self, cost_table: CostTable) -> VmBuilder {
+        self.cost_table = cost_table;
+        self
+    }
+
+    // Integer arithmetic wraps around by default, checked arithmetic turns
+    // overflows into runtime errors.
+    pub fn checked_arithmetic(mut self, checked_arithmetic: bool) -> VmBuilder {
+        self.checked_arithmetic = checked_arithmetic;
+        self
+    }
+
+    // Input and output of the I/O native procedures, stdin and stdout by default.
+    pub fn io(mut self, io: Io) -> VmBuilder {
+        self.io = io;
+        self
+    }
+
+    pub fn trace(mut self, trace: Trace) -> VmBuilder {
+        self.trace = Some(trace);
+        self
+    }
+
+    // Records the results of nondeterministic native procedures, or replays
+    // them from an earlier recording.
+    pub fn recording(mut self, recording: Recording) -> VmBuilder {
+        self.recording = Some(recording);
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        let mut native_procedures: NativeProceduresMap = HashMap::new();
+        natives::register_standard(&mut native_procedures);
+
+        let mut procedure_table = HashMap::new();
+        for (position, procedure) in self.procedures.iter().enumerate() {
+            procedure_table.insert(procedure.index, position);
+        }
+
+        let mut allocation_array = AllocationArray::new();
+        allocation_array.set_max_words(self.limits.max_heap_words);
+
+        Vm {
+            procedures: self.procedures,
+            procedure_table,
+            native_procedures,
+            limits: self.limits,
+            fuel: self.fuel,
+            fuel_consumed: 0,
+            cost_table: self.cost_table,
+            checked_arithmetic: self.checked_arithmetic,
+            io: self.io,
+            trace: self.trace,
+            recording: self.recording,
+            frames: Vec::new(),
+            error: None,
+            retrying: false,
+            failed_call_frames: Vec::new(),
+            stack: VecDeque::new(),
+            allocation_array,
+        }
+    }
+}
+
+pub struct Vm {
+    procedures: VecDeque<Procedure>,
+    // CVM procedure index -> position in `procedures`
+    procedure_table: HashMap<u64, usize>,
+    native_procedures: NativeProceduresMap,
+    limits: Limits,
+    // Remaining fuel, None if execution is not metered
+    fuel: Option<u64>,
+    fuel_consumed: u64,
+    cost_table: CostTable,
+    checked_arithmetic: bool,
+    io: Io,
+    trace: Option<Trace>,
+    recording: Option<Recording>,
+    // The last frame is the procedure being executed
+    frames: Vec<CallFrame>,
+    // The runtime error the program failed with, it cannot continue from it
+    error: Option<RuntimeError>,
+    // Set when a NAT stopped to wait for input, it was reported to the hooks
+    // and the trace already and is not reported again when it runs
+    retrying: bool,
+    // The frames of a host call that failed, the stack trace shows them until
+    // the VM executes again
+    failed_call_frames: Vec<CallFrame>,
+    pub stack: VecDeque<u64>,
+    pub allocation_array: AllocationArray,
+}
+
+impl Vm {
+    pub fn builder(procedures: VecDeque<Procedure>) -> VmBuilder {
+        VmBuilder {
+            procedures,
+            limits: Limits::default(),
+            fuel: None,
+            cost_table: CostTable::new(),
+            checked_arithmetic: false,
+            io: Io::default(),
+            trace: None,
+            recording: None,
+        }
+    }
+
+    // Creates a VM for the given procedures with the standard native
+    // procedures registered and no limits.
+    pub fn new(procedures: VecDeque<Procedure>) -> Vm {
+        Vm::builder(procedures).build()
+    }
+
+    pub fn register_native_procedure<F>(&mut self, index: u64, name: &'static str, function: F)
+    where
+        F: Fn(&mut NativeContext) -> Result<(), NativeError> + 'static,
+    {
+        self.native_procedures.insert(
+            index,
+            NativeProcedure {
+                name,
+                function: Box::new(function),
+                deterministic: true,
+            },
+        );
+    }
+
+    // Registers a native procedure whose results can differ between runs, so
+    // that they are recorded and replayed.
+    pub fn register_nondeterministic_native_procedure<F>(
+        &mut self,
+        index: u64,
+        name: &'static str,
+        function: F,
+    ) where
+        F: Fn(&mut NativeContext) -> Result<(), NativeError> + 'static,
+    {
+        self.native_procedures.insert(
+            index,
+            NativeProcedure {
+                name,
+                function: Box::new(function),
+                deterministic: false,
+            },
+        );
+    }
+
+    pub fn has_procedure(&self, index: u64) -> bool {
+        self.procedure_table.contains_key(&index)
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    // Procedures on the call stack with the position of the instruction they
+    // executed last, innermost first. After a runtime error this is the stack
+    // trace of the error, also when it happened in a call from the host.
+    pub fn stack_trace(&self) -> Vec<(&Procedure, error::Position)> {
+        let frames = if self.failed_call_frames.is_empty() {
+            &self.frames
+        } else {
+            &self.failed_call_frames
+        };
+
+        frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let procedure = &self.procedures[frame.procedure];
+                let position = procedure
+                    .positions
+                    .get(frame.pc.saturating_sub(1) as usize)
+                    .cloned()
+                    .unwrap_or(error::Position { line: 0, column: 0 });
+
+                (procedure, position)
+            })
+            .collect()
+    }
+
+    pub fn procedures(&self) -> &VecDeque<Procedure> {
+        &self.procedures
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed
+    }
+
+    // Adds fuel to a metered VM. An unmetered VM stays unmetered.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(remaining) = &mut self.fuel {
+            *remaining = remaining.saturating_add(fuel);
+        }
+    }
+
+    // Runs the main procedure (index 0) until it stops or runs out of fuel.
+    pub fn run(&mut self) -> Result<Status, RuntimeError> {
+        self.run_procedure(0)
+    }
+
+    pub fn run_with<H: Hooks>(&mut self, hooks: &mut H) -> Result<Status, RuntimeError> {
+        self.run_procedure_with(0, hooks)
+    }
+
+    // Runs the procedure with the given CVM index as if it was the main one, on
+    // top of the current value stack.
+    pub fn run_procedure(&mut self, index: u64) -> Result<Status, RuntimeError> {
+        self.run_procedure_with(index, &mut NoHooks)
+    }
+
+    pub fn run_procedure_with<H: Hooks>(
+        &mut self,
+        index: u64,
+        hooks: &mut H,
+    ) -> Result<Status, RuntimeError> {
+        self.start(index)?;
+        self.continue_execution(None, hooks)
+    }
+
+    // Calls the procedure with the given CVM index like WYWOŁAJ does, with the
+    // arguments pushed in order, and returns the value WRÓĆ leaves in place of
+    // them. Procedures without parameters may leave nothing, then it is None.
+    // The value stack is as before the call afterwards, the heap is shared
+    // with the program and kept. A program paused by step or run_for continues
+    // where it was after the call.
+    pub fn call(&mut self, index: u64, arguments: &[u64]) -> Result<Option<u64>, RuntimeError> {
+        match self.procedure_table.get(&index) {
+            Some(&position) => self.call_at(position, arguments),
+            None => Err(host_error(RuntimeErrorKind::ProcedureNotFound(index))),
+        }
+    }
+
+    // Calls the procedure with the given @Procedura name, see `call`.
+    pub fn call_by_name(
+        &mut self,
+        name: &str,
+        arguments: &[u64],
+    ) -> Result<Option<u64>, RuntimeError> {
+        match self
+            .procedures
+            .iter()
+            .position(|procedure| procedure.name == name)
+        {
+            Some(position) => self.call_at(position, arguments),
+            None => Err(host_error(RuntimeErrorKind::ProcedureNameNotFound(
+                name.to_owned(),
+            ))),
+        }
+    }
+
+    fn call_at(&mut self, position: usize, arguments: &[u64]) -> Result<Option<u64>, RuntimeError> {
+        self.failed_call_frames.clear();
+        let procedure = &self.procedures[position];
+        let index = procedure.index;
+
+        if procedure.parameter_count as usize != arguments.len() {
+            return Err(host_error(RuntimeErrorKind::ArgumentCountMismatch(
+                index,
+                procedure.parameter_count,
+                arguments.len(),
+            )));
+        }
+
+        let bottom = self.stack.len();
+        self.stack.extend(arguments);
+
+        // Procedures without code return right away, like with WYWOŁAJ
+        let mut returned = ReturnWatcher { depth: 0 };
+        let status = if procedure.code.is_empty() {
+            returned.depth = -1;
+            Ok(Status::Halted)
+        } else {
+            let caller_frames = mem::take(&mut self.frames);
+            let caller_retrying = mem::take(&mut self.retrying);
+            self.frames.push(CallFrame {
+                procedure: position,
+                pc: 0,
+                bottom: bottom as u64,
+            });
+
+            let status = self.execute(None, &mut returned);
+            let callee_frames = mem::replace(&mut self.frames, caller_frames);
+            self.retrying = caller_retrying;
+
+            if status.is_err() {
+                self.failed_call_frames = callee_frames;
+            }
+
+            status
+        };
+
+        let result = match status {
+            Ok(Status::Halted) if returned.depth < 0 => Ok(self.stack.get(bottom).copied()),
+            Ok(status) => Err(host_error(RuntimeErrorKind::CallNotFinished(index, status))),
+            Err(error) => Err(error),
+        };
+
+        self.stack.truncate(bottom);
+        result
+    }
+
+    // Prepares the call stack for running the given procedure without executing
+    // anything, execution continues with step or resume.
+    pub fn start(&mut self, index: u64) -> Result<(), RuntimeError> {
+        let entry = match self.procedure_table.get(&index) {
+            Some(&entry) => entry,
+            None => {
+                return Err(error::Error {
+                    position: error::Position { line: 0, column: 0 },
+                    kind: RuntimeErrorKind::ProcedureNotFound(index),
+                })
+            }
+        };
+
+        self.frames.clear();
+        self.failed_call_frames.clear();
+        self.error = None;
+        self.retrying = false;
+        self.frames.push(CallFrame {
+            procedure: entry,
+            pc: 0,
+            bottom: 0,
+        });
+
+        Ok(())
+    }
+
+    // Continues execution stopped by running out of fuel or by stepping. After
+    // a runtime error it returns the same error again, like step and run_for.
+    pub fn resume(&mut self) -> Result<Status, RuntimeError> {
+        self.continue_execution(None, &mut NoHooks)
+    }
+
+    pub fn resume_with<H: Hooks>(&mut self, hooks: &mut H) -> Result<Status, RuntimeError> {
+        self.continue_execution(None, hooks)
+    }
+
+    // Executes a single instruction.
+    pub fn step(&mut self) -> Result<Status, RuntimeError> {
+        self.continue_execution(Some(1), &mut NoHooks)
+    }
+
+    pub fn step_with<H: Hooks>(&mut self, hooks: &mut H) -> Result<Status, RuntimeError> {
+        self.continue_execution(Some(1), hooks)
+    }
+
+    // Executes at most the given number of instructions, for hosts running the
+    // program a bit at a time.
+    pub fn run_for(&mut self, instructions: u64) -> Result<Status, RuntimeError> {
+        self.continue_execution(Some(instructions), &mut NoHooks)
+    }
+
+    pub fn run_for_with<H: Hooks>(
+        &mut self,
+        instructions: u64,
+        hooks: &mut H,
+    ) -> Result<Status, RuntimeError> {
+        self.continue_execution(Some(instructions), hooks)
+    }
+
+    // True once the program stopped, returned from its entry procedure or
+    // failed with a runtime error it cannot continue from.
+    pub fn is_finished(&self) -> bool {
+        self.frames.is_empty() || self.error.is_some()
+    }
+
+    // The runtime error the program failed with. The call stack is kept as it
+    // was for `stack_trace`, `start` clears it.
+    pub fn error(&self) -> Option<&RuntimeError> {
+        self.error.as_ref()
+    }
+
+    pub fn io_mut(&mut self) -> &mut Io {
+        &mut self.io
+    }
+
+    pub fn heap_report(&self) -> HeapReport {
+        self.allocation_array.report()
+    }
+
+    // Identifies the program in snapshots, see `snapshot::program_hash`.
+    pub fn program_hash(&self) -> u64 {
+        snapshot::program_hash(&self.procedures)
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            frames: self.frames.clone(),
+            stack: self.stack.clone(),
+            allocation_array: self.allocation_array.clone(),
+            fuel: self.fuel,
+            fuel_consumed: self.fuel_consumed,
+            recording_position: self
+                .recording
+                .as_ref()
+                .and_then(|recording| recording.position()),
+            error: self.error.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: &Checkpoint) {
+        self.frames = checkpoint.frames.clone();
+        self.stack = checkpoint.stack.clone();
+        self.allocation_array = checkpoint.allocation_array.clone();
+        self.fuel = checkpoint.fuel;
+        self.fuel_consumed = checkpoint.fuel_consumed;
+        self.error = checkpoint.error.clone();
+        self.retrying = false;
+        self.failed_call_frames.clear();
+
+        if let (Some(recording), Some(position)) =
+            (&mut self.recording, checkpoint.recording_position)
+        {
+            recording.set_position(position);
+        }
+    }
+
+    // Executes the program unless it failed before, and keeps the runtime
+    // error it fails with. Host calls do not go through here, their errors do
+    // not end the program.
+    fn continue_execution<H: Hooks>(
+        &mut self,
+        steps: Option<u64>,
+        hooks: &mut H,
+    ) -> Result<Status, RuntimeError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+
+        self.failed_call_frames.clear();
+        let status = self.execute(steps, hooks);
+
+        if let Err(error) = &status {
+            self.error = Some(error.clone());
+        }
+
+        status
+    }
+
+    // Executes instructions until the program finishes, fuel runs out or `steps`
+    // instructions were executed.
+    fn execute<H: Hooks>(
+        &mut self,
+        steps: Option<u64>,
+        hooks: &mut H,
+    ) -> Result<Status, RuntimeError> {
+        let Vm {
+            procedures,
+            procedure_table,
+            native_procedures,
+            limits,
+            fuel,
+            fuel_consumed,
+            cost_table,
+            checked_arithmetic,
+            io,
+            trace,
+            recording,
+            frames,
+            error: _,
+            retrying,
+            failed_call_frames: _,
+            stack,
+            allocation_array,
+        } = self;
+
+        // Drops allocations the host made outside of native procedures
+        allocation_array.take_events();
+
+        let mut stopped = false;
+        let interpreter = Interpreter {
+            procedures,
+            procedure_table,
+            native_procedures,
+            limits,
+            fuel,
+            fuel_consumed,
+            cost_table,
+            checked_arithmetic,
+            trace,
+            recording: recording.as_mut(),
+            frames,
+            stopped: &mut stopped,
+            retrying,
+        };
+        let status = interpreter.execute(stack, allocation_array, io, 0, steps, hooks);
+
+        // Hosts may exit right after a runtime error, so nothing can stay
+        // buffered
+        if let Some(trace) = &mut self.trace {
+            let _ = trace.sink.flush();
+        }
+
+        status
+    }
+}
+
+// The parts of the VM executing instructions needs besides the ones native
+// procedures get directly. Natives get it to call procedures.
+pub(crate) struct Interpreter<'a> {
+    procedures: &'a VecDeque<Procedure>,
+    procedure_table: &'a HashMap<u64, usize>,
+    native_procedures: &'a NativeProceduresMap,
+    limits: &'a Limits,
+    fuel: &'a mut Option<u64>,
+    fuel_consumed: &'a mut u64,
+    cost_table: &'a CostTable,
+    checked_arithmetic: &'a bool,
+    trace: &'a mut Option<Trace>,
+    recording: Option<&'a mut Recording>,
+    frames: &'a mut Vec<CallFrame>,
+    // Set by STOP, so that native procedures calling back into the program
+    // stop too
+    stopped: &'a mut bool,
+    retrying: &'a mut bool,
+}
+
+impl Interpreter<'_> {
+    fn reborrow(&mut self) -> Interpreter<'_> {
+        Interpreter {
+            procedures: self.procedures,
+            procedure_table: self.procedure_table,
+            native_procedures: self.native_procedures,
+            limits: self.limits,
+            fuel: self.fuel,
+            fuel_consumed: self.fuel_consumed,
+            cost_table: self.cost_table,
+            checked_arithmetic: self.checked_arithmetic,
+            trace: self.trace,
+            recording: self.recording.as_deref_mut(),
+            frames: self.frames,
+            stopped: self.stopped,
+            retrying: self.retrying,
+        }
+    }
+
+    // Executes instructions until the frames above `base` return, the program
+    // finishes, fuel runs out or `steps` instructions were executed.
+    fn execute<H: Hooks>(
+        self,
+        stack: &mut VecDeque<u64>,
+        allocation_array: &mut AllocationArray,
+        io: &mut Io,
+        base: usize,
+        mut steps: Option<u64>,
+        hooks: &mut H,
+    ) -> Result<Status, RuntimeError> {
+        let Interpreter {
+            procedures,
+            procedure_table,
+            native_procedures,
+            limits,
+            fuel,
+            fuel_consumed,
+            cost_table,
+            checked_arithmetic,
+            trace,
+            mut recording,
+            frames,
+            stopped,
+            retrying,
+        } = self;
+
+        while frames.len() > base {
+            let frame = frames.last_mut().unwrap();
+            let procedure = &procedures[frame.procedure];
+            let pc = frame.pc;
+            let bottom = frame.bottom;
+            let instruction = procedure.code[pc as usize];
+
+            if let Some(steps) = &mut steps {
+                if *steps == 0 {
+                    return Ok(Status::Paused);
+                }
+
+                *steps -= 1;
+            }
+
+            if let Some(remaining) = fuel {
+                let cost = cost_table.cost_of(&instruction);
+
+                if cost > *remaining {
+                    return Ok(Status::OutOfFuel);
+                }
+
+                *remaining -= cost;
+                *fuel_consumed += cost;
+            }
+
+            // A NAT that waited for input was reported before it waited, so
+            // polling for input adds nothing to traces and profiles
+            let reported = mem::take(retrying);
+
+            if !reported {
+                if let Some(trace) = trace {
+                    trace.before_instruction(procedure, pc, stack);
+                }
+
+                hooks.before_instruction(procedure, pc, stack);
+            }
+
+            frame.pc += 1;
+
+            match instruction {
+                // Stack
+                Instruction::Pchnij(value) => {
+                    stack.push_back(value);
+                }
+                Instruction::Usun => drop(stack.pop_back()),
+                Instruction::ZmiennaK(index) => {
+                    stack.push_back(stack[(bottom + index) as usize]);
+                }
+                Instruction::ZmiennaU(index) => {
+                    let x = stack.pop_back().unwrap();
+                    stack[(bottom + index) as usize] = x;
+                }
+
+                // Stack manipulation
+                Instruction::Powiel => {
+                    if stack.is_empty() {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::StackUnderflow(instruction),
+                        );
+                    }
+
+                    stack.push_back(*stack.back().unwrap());
+                }
+                Instruction::Zamien => {
+                    if stack.len() < 2 {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::StackUnderflow(instruction),
+                        );
+                    }
+
+                    let len = stack.len();
+                    stack.swap(len - 1, len - 2);
+                }
+                Instruction::Nad => {
+                    if stack.len() < 2 {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::StackUnderflow(instruction),
+                        );
+                    }
+
+                    stack.push_back(stack[stack.len() - 2]);
+                }
+                Instruction::Obroc => {
+                    if stack.len() < 3 {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::StackUnderflow(instruction),
+                        );
+                    }
+
+                    let x = stack.remove(stack.len() - 3).unwrap();
+                    stack.push_back(x);
+                }
+                Instruction::Wybierz(depth) => {
+                    if stack.len() as u64 <= depth {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::StackUnderflow(instruction),
+                        );
+                    }
+
+                    stack.push_back(stack[stack.len() - 1 - depth as usize]);
+                }
+
+                // Arithemtics
+                Instruction::DodajC => {
+                    if cvm_arithmetics_u64!(stack, i64::overflowing_add) && *checked_arithmetic {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::IntegerOverflow(instruction),
+                        );
+                    }
+                }
+                Instruction::DodajZ => {
+                    cvm_arithmetics_f64!(stack, +);
+                }
+
+                Instruction::OdejmC => {
+                    if cvm_arithmetics_u64!(stack, i64::overflowing_sub) && *checked_arithmetic {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::IntegerOverflow(instruction),
+                        );
+                    }
+                }
+                Instruction::OdejmZ => {
+                    cvm_arithmetics_f64!(stack, -);
+                }
+
+                Instruction::MnozC => {
+                    if cvm_arithmetics_u64!(stack, i64::overflowing_mul) && *checked_arithmetic {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::IntegerOverflow(instruction),
+                        );
+                    }
+                }
+                Instruction::MnozZ => {
+                    cvm_arithmetics_f64!(stack, *);
+                }
+
+                Instruction::DzielC => {
+                    if *stack.back().unwrap() == 0 {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::DivisionByZero(instruction),
+                        );
+                    }
+
+                    if cvm_arithmetics_u64!(stack, floor_div) && *checked_arithmetic {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::IntegerOverflow(instruction),
+                        );
+                    }
+                }
+                Instruction::DzielZ => {
+                    cvm_arithmetics_f64!(stack, /);
+                }
+
+                Instruction::ResztaC => {
+                    if *stack.back().unwrap() == 0 {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::DivisionByZero(instruction),
+                        );
+                    }
+
+                    if cvm_arithmetics_u64!(stack, floor_mod) && *checked_arithmetic {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::IntegerOverflow(instruction),
+                        );
+                    }
+                }
+                Instruction::ResztaZ => {
+                    let y = f64::from_bits(stack.pop_back().unwrap());
+                    let x = f64::from_bits(stack.pop_back().unwrap());
+                    stack.push_back(float_mod(x, y).to_bits());
+                }
+
+                Instruction::JakoCZ => {
+                    let num = stack.pop_back().unwrap() as i64;
+
+                    stack.push_back((num as f64).to_bits());
+                }
+                Instruction::JakoZC => {
+                    let num = stack.pop_back().unwrap();
+
+                    stack.push_back(float_to_int(f64::from_bits(num)) as u64);
+                }
+
+                // Comparisons
+                Instruction::NieL => {
+                    let x = stack.pop_back().unwrap() as i64;
+                    if x == 0 {
+                        stack.push_back(1);
+                    } else {
+                        stack.push_back(0);
+                    }
+                }
+                Instruction::Rowne => {
+                    let y = stack.pop_back().unwrap() as i64;
+                    let x = stack.pop_back().unwrap() as i64;
+                    stack.push_back((x == y) as u64);
+                }
+                Instruction::RowneZ => {
+                    let y = f64::from_bits(stack.pop_back().unwrap());
+                    let x = f64::from_bits(stack.pop_back().unwrap());
+                    stack.push_back((x == y) as u64);
+                }
+
+                Instruction::MniejC => {
+                    let y = stack.pop_back().unwrap() as i64;
+                    let x = stack.pop_back().unwrap() as i64;
+                    stack.push_back((x < y) as u64);
+                }
+                Instruction::MniejZ => {
+                    let y = f64::from_bits(stack.pop_back().unwrap());
+                    let x = f64::from_bits(stack.pop_back().unwrap());
+                    stack.push_back((x < y) as u64);
+                }
+
+                Instruction::MNrowC => {
+                    let y = stack.pop_back().unwrap() as i64;
+                    let x = stack.pop_back().unwrap() as i64;
+                    stack.push_back((x <= y) as u64);
+                }
+                Instruction::MNrowZ => {
+                    let y = f64::from_bits(stack.pop_back().unwrap());
+                    let x = f64::from_bits(stack.pop_back().unwrap());
+                    stack.push_back((x <= y) as u64);
+                }
+
+                // Bitwise operations
+                Instruction::NieB => {
+                    let x = stack.pop_back().unwrap();
+                    stack.push_back(!x);
+                }
+                Instruction::I => {
+                    let y = stack.pop_back().unwrap();
+                    let x = stack.pop_back().unwrap();
+                    stack.push_back(x & y);
+                }
+                Instruction::Lub => {
+                    let y = stack.pop_back().unwrap();
+                    let x = stack.pop_back().unwrap();
+                    stack.push_back(x | y);
+                }
+                Instruction::XLub => {
+                    let y = stack.pop_back().unwrap();
+                    let x = stack.pop_back().unwrap();
+                    stack.push_back(x ^ y);
+                }
+                Instruction::PrzesunL => {
+                    let y = stack.pop_back().unwrap();
+                    let x = stack.pop_back().unwrap();
+                    stack.push_back(shift_left(x, y));
+                }
+                Instruction::PrzesunR => {
+                    let y = stack.pop_back().unwrap();
+                    let x = stack.pop_back().unwrap();
+                    stack.push_back(shift_right(x, y));
+                }
+
+                // PC register manipulation
+                Instruction::IdzDo(new_pc) => {
+                    frame.pc = new_pc;
+                }
+                Instruction::IdzDoZe(new_pc) => {
+                    let x = stack.pop_back().unwrap();
+
+                    if x == 0 {
+                        frame.pc = new_pc;
+                    }
+                }
+                Instruction::IdzDoNz(new_pc) => {
+                    let x = stack.pop_back().unwrap();
+
+                    if x != 0 {
+                        frame.pc = new_pc;
+                    }
+                }
+                Instruction::IdzDoS => {
+                    if stack.is_empty() {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::StackUnderflow(instruction),
+                        );
+                    }
+
+                    let new_pc = stack.pop_back().unwrap();
+
+                    if new_pc as usize >= procedure.code.len() {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::JumpOutOfBounds(new_pc, procedure.code.len()),
+                        );
+                    }
+
+                    frame.pc = new_pc;
+                }
+                Instruction::Wywolaj(_) | Instruction::WywolajS => {
+                    let proc_idx = match instruction {
+                        Instruction::Wywolaj(proc_idx) => proc_idx,
+                        _ => match stack.pop_back() {
+                            Some(proc_idx) => proc_idx,
+                            None => {
+                                return runtime_error(
+                                    procedure,
+                                    pc,
+                                    RuntimeErrorKind::StackUnderflow(instruction),
+                                );
+                            }
+                        },
+                    };
+
+                    let new_proc = match procedure_table.get(&proc_idx) {
+                        Some(&new_proc) => new_proc,
+                        None => {
+                            return runtime_error(
+                                procedure,
+                                pc,
+                                RuntimeErrorKind::ProcedureNotFound(proc_idx),
+                            );
+                        }
+                    };
+
+                    // Procedures without code return right away
+                    if !procedures[new_proc].code.is_empty() {
+                        if let Some(max_call_depth) = limits.max_call_depth {
+                            if frames.len() >= max_call_depth {
+                                return runtime_error(
+                                    procedure,
+                                    pc,
+                                    RuntimeErrorKind::CallDepthExceeded(max_call_depth),
+                                );
+                            }
+                        }
+
+                        frames.push(CallFrame {
+                            procedure: new_proc,
+                            pc: 0,
+                            bottom: (stack.len() - procedures[new_proc].parameter_count as usize)
+                                as u64,
+                        });
+
+                        hooks.call(procedure, pc, &procedures[new_proc]);
+                    }
+                }
+                Instruction::Wroc => {
+                    frames.pop();
+
+                    if procedure.index != 0 {
+                        stack.truncate((bottom + 1) as usize);
+                    }
+
+                    hooks.ret(procedure);
+                }
+                Instruction::Stop => {
+                    // Frames below `base` belong to the native procedures
+                    // that called back into the program, they stop as well
+                    frames.truncate(base);
+                    *stopped = true;
+                }
+
+                // Interpreter communication
+                Instruction::Nat(nat_proc) => match native_procedures.get(&nat_proc) {
+                    Some(native_procedure) => {
+                        if !reported {
+                            hooks.native_call(nat_proc, native_procedure.name, stack);
+                        }
+
+                        // A nondeterministic native procedure is recorded as a
+                        // whole, including the procedures it calls
+                        let (recording, callback_recording) = match recording.as_deref_mut() {
+                            Some(recording) if !native_procedure.deterministic => {
+                                (Some(recording), None)
+                            }
+                            recording => (None, recording),
+                        };
+
+                        let mut context = NativeContext {
+                            stack,
+                            allocation_array,
+                            io,
+                            position: &procedure.positions[pc as usize],
+                            interpreter: Interpreter {
+                                procedures,
+                                procedure_table,
+                                native_procedures,
+                                limits,
+                                fuel,
+                                fuel_consumed,
+                                cost_table,
+                                checked_arithmetic,
+                                trace,
+                                recording: callback_recording,
+                                frames,
+                                stopped: &mut *stopped,
+                                retrying: &mut *retrying,
+                            },
+                            hooks,
+                        };
+
+                        let result = match recording {
+                            Some(recording) => {
+                                recording.call(nat_proc, &*native_procedure.function, &mut context)
+                            }
+                            None => (native_procedure.function)(&mut context),
+                        };
+
+                        // A STOP in a procedure the native called stops the
+                        // program whatever the native returned
+                        if *stopped {
+                            frames.truncate(base);
+                            return Ok(Status::Halted);
+                        }
+
+                        if let Err(NativeError::InputNotReady) = result {
+                            // Nothing happened, the NAT is executed again later
+                            frames.last_mut().unwrap().pc = pc;
+                            *retrying = true;
+
+                            if let Some(remaining) = fuel {
+                                let cost = cost_table.cost_of(&instruction);
+                                *remaining += cost;
+                                *fuel_consumed -= cost;
+                            }
+
+                            return Ok(Status::WaitingForInput);
+                        }
+
+                        if let Err(NativeError::Call(error)) = result {
+                            return Err(*error);
+                        }
+
+                        if let Err(error) = result {
+                            return runtime_error(
+                                procedure,
+                                pc,
+                                RuntimeErrorKind::NativeProcedureFailed(
+                                    native_procedure.name,
+                                    nat_proc,
+                                    error,
+                                ),
+                            );
+                        }
+
+                        for event in allocation_array.take_events() {
+                            match event {
+                                HeapEvent::Alloc { handle, size } => hooks.heap_alloc(handle, size),
+                                HeapEvent::Free { handle } => hooks.heap_free(handle),
+                            }
+                        }
+                    }
+                    None => {
+                        return runtime_error(
+                            procedure,
+                            pc,
+                            RuntimeErrorKind::NativeProcedureNotFound(nat_proc),
+                        );
+                    }
+                },
+                Instruction::BrakOperacji => unimplemented!(),
+            }
+
+            if let Some(max_stack_length) = limits.max_stack_length {
+                if stack.len() > max_stack_length {
+                    return runtime_error(
+                        procedure,
+                        pc,
+                        RuntimeErrorKind::StackLimitExceeded(max_stack_length),
+                    );
+                }
+            }
+
+            hooks.after_instruction(procedure, pc, stack);
+        }
+
+        Ok(Status::Halted)
+    }
+}
+
+impl NativeContext<'_> {
+    // Calls the procedure with the given CVM index on top of the call stack,
+    // like WYWOŁAJ from the NAT calling the native procedure would, and returns
+    // the value it leaves in place of the arguments (see `Vm::call`). Limits
+    // and fuel apply to it as to the rest of the program. Its instructions run
+    // to its WRÓĆ even when the VM runs a few instructions at a time.
+    //
+    // An error of the procedure is returned as `NativeError::Call`. Returning
+    // it from the native procedure fails the program with that error.
+    pub fn call(&mut self, index: u64, arguments: &[u64]) -> Result<Option<u64>, NativeError> {
+        let position = self.position;
+        let call_error = |kind| {
+            NativeError::Call(Box::new(error::Error {
+                position: position.clone(),
+                kind,
+            }))
+        };
+
+        let interpreter = &mut self.interpreter;
+        if *interpreter.stopped {
+            return Err(call_error(RuntimeErrorKind::CallNotFinished(
+                index,
+                Status::Halted,
+            )));
+        }
+
+        let callee = match interpreter.procedure_table.get(&index) {
+            Some(&callee) => callee,
+            None => return Err(call_error(RuntimeErrorKind::ProcedureNotFound(index))),
+        };
+        let procedure = &interpreter.procedures[callee];
+
+        if procedure.parameter_count as usize != arguments.len() {
+            return Err(call_error(RuntimeErrorKind::ArgumentCountMismatch(
+                index,
+                procedure.parameter_count,
+                arguments.len(),
+            )));
+        }
+
+        if let Some(max_call_depth) = interpreter.limits.max_call_depth {
+            if interpreter.frames.len() >= max_call_depth {
+                return Err(call_error(RuntimeErrorKind::CallDepthExceeded(
+                    max_call_depth,
+                )));
+            }
+        }
+
+        let bottom = self.stack.len();
+        self.stack.extend(arguments);
+
+        // Procedures without code return right away, like with WYWOŁAJ
+        let base = interpreter.frames.len();
+        let status = if procedure.code.is_empty() {
+            Ok(Status::Halted)
+        } else {
+            if let Some(caller) = interpreter.frames.last() {
+                self.hooks.call(
+                    &interpreter.procedures[caller.procedure],
+                    caller.pc - 1,
+                    procedure,
+                );
+            }
+
+            interpreter.frames.push(CallFrame {
+                procedure: callee,
+                pc: 0,
+                bottom: bottom as u64,
+            });
+
+            interpreter.reborrow().execute(
+                self.stack,
+                self.allocation_array,
+                self.io,
+                base,
+                None,
+                &mut self.hooks,
+            )
+        };
+
+        if *interpreter.stopped {
+            // The value stack is left as STOP found it
+            return Err(call_error(RuntimeErrorKind::CallNotFinished(
+                index,
+                Status::Halted,
+            )));
+        }
+
+        let result = match status {
+            Ok(Status::Halted) if interpreter.frames.len() == base => {
+                Ok(self.stack.get(bottom).copied())
+            }
+            Ok(status) => Err(call_error(RuntimeErrorKind::CallNotFinished(index, status))),
+            Err(error) => Err(NativeError::Call(Box::new(error))),
+        };
+
+        // The native procedure may carry on after an error, a NAT of the
+        // callee waiting for input is not run again
+        interpreter.frames.truncate(base);
+        *interpreter.retrying = false;
+        self.stack.truncate(bottom);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Main calls 1, 1 runs the native, which calls 2 back
+    const PROGRAM: &str = "@CVMA 1
+@Procedura 0 \"main\" 0
+PCHNIJ 5
+WYWOŁAJ 1
+PCHNIJ 6
+WRÓĆ
+
+@Procedura 1 \"wołający\" 0
+NAT 100
+PCHNIJ 7
+WRÓĆ
+
+@Procedura 2 \"zatrzymaj\" 0
+PCHNIJ 8
+STOP
+WRÓĆ
+
+@Procedura 3 \"dziewięć\" 0
+PCHNIJ 9
+WRÓĆ
+";
+
+    fn vm_calling_back<F>(function: F) -> Vm
+    where
+        F: Fn(&mut NativeContext) -> Result<(), NativeError> + 'static,
+    {
+        let cvma_file = crate::parse::read_from_string(PROGRAM);
+        assert!(cvma_file.errors.is_empty());
+
+        let mut vm = Vm::new(cvma_file.procedures);
+        vm.register_native_procedure(0x100, "Wołaj", function);
+        vm
+    }
+
+    #[test]
+    fn failed_program_does_not_continue() {
+        let cvma_file = crate::parse::read_from_string(
+            "@CVMA 1\n@Procedura 0 \"main\" 0\nPCHNIJ 7\nPCHNIJ 0\nDZIEL.C\nPCHNIJ 9\nSTOP\nWRÓĆ\n",
+        );
+        assert!(cvma_file.errors.is_empty());
+
+        let mut vm = Vm::new(cvma_file.procedures);
+        vm.start(0).unwrap();
+
+        let error = vm.run_for(100).unwrap_err();
+        assert_eq!(error.kind.get_message(), "division by zero in DZIEL.C");
+        assert!(vm.is_finished());
+        assert_eq!(vm.error().unwrap().position.line, 5);
+        assert_eq!(vm.stack_trace().len(), 1);
+
+        // Every way of continuing fails with the same error
+        for status in [vm.run_for(100), vm.step(), vm.resume()] {
+            assert_eq!(status.unwrap_err().position.line, 5);
+        }
+        assert_eq!(vm.stack, [7, 0]);
+
+        // Starting again clears the error
+        vm.start(0).unwrap();
+        assert!(!vm.is_finished());
+        assert!(vm.error().is_none());
+    }
+
+    // Hooks writing down the instructions and native procedures they see
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl Hooks for Events {
+        fn before_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+            self.0
+                .push(format!("{}:{} {:?}", procedure.index, pc, stack));
+        }
+
+        fn native_call(&mut self, index: u64, _name: &'static str, _stack: &VecDeque<u64>) {
+            self.0.push(format!("NAT {:X}", index));
+        }
+    }
+
+    #[test]
+    fn nat_waiting_for_input_is_reported_once_before_it_runs() {
+        let cvma_file = crate::parse::read_from_string(
+            "@CVMA 1\n@Procedura 0 \"main\" 0\nPCHNIJ 1\nNAT 3\nSTOP\nWRÓĆ\n",
+        );
+        assert!(cvma_file.errors.is_empty());
+
+        let input = natives::InputBuffer::default();
+        let io = Io {
+            input: Box::new(std::io::BufReader::new(input.clone())),
+            output: Box::new(std::io::sink()),
+        };
+        let mut vm = Vm::builder(cvma_file.procedures).io(io).build();
+        let mut events = Events::default();
+
+        vm.start(0).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                vm.run_for_with(10, &mut events),
+                Ok(Status::WaitingForInput)
+            ));
+        }
+
+        input.push_str("7\n");
+        assert!(matches!(
+            vm.run_for_with(10, &mut events),
+            Ok(Status::Halted)
+        ));
+        assert_eq!(events.0, ["0:0 []", "0:1 [1]", "NAT 3", "0:2 [1, 7]"]);
+    }
+
+    #[test]
+    fn unreadable_input_is_a_runtime_error() {
+        let cvma_file =
+            crate::parse::read_from_string("@CVMA 1\n@Procedura 0 \"main\" 0\nNAT 3\nSTOP\nWRÓĆ\n");
+        assert!(cvma_file.errors.is_empty());
+
+        let io = Io {
+            input: Box::new(&b"\xFF\xFE\n"[..]),
+            output: Box::new(std::io::sink()),
+        };
+        let mut vm = Vm::builder(cvma_file.procedures).io(io).build();
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.position.line, 3);
+        assert_eq!(
+            error.kind.get_message(),
+            "native procedure GetC (3): failed to read input: invalid data"
+        );
+    }
+
+    #[test]
+    fn nat_is_reported_before_the_procedures_it_calls() {
+        let mut vm = vm_calling_back(|_| Ok(()));
+        // Natives that can wait for input are reported the same way
+        vm.register_nondeterministic_native_procedure(0x100, "Wołaj", |context| {
+            context.call(3, &[])?;
+            Ok(())
+        });
+        let mut events = Events::default();
+
+        assert!(matches!(vm.run_with(&mut events), Ok(Status::Halted)));
+        assert_eq!(
+            events.0,
+            [
+                "0:0 []",
+                "0:1 [5]",
+                "1:0 [5]",
+                "NAT 100",
+                "3:0 [5]",
+                "3:1 [5, 9]",
+                "1:1 [5]",
+                "1:2 [5, 7]",
+                "0:2 [5, 7]",
+                "0:3 [5, 7, 6]",
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_in_callback_stops_the_program() {
+        let mut vm = vm_calling_back(|context| {
+            let error = context.call(2, &[]).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "procedure 2 did not return: the program was stopped"
+            );
+            // Nothing runs after the STOP
+            assert!(context.call(2, &[]).is_err());
+            Ok(())
+        });
+
+        assert!(matches!(vm.run(), Ok(Status::Halted)));
+        assert!(vm.is_finished());
+        assert_eq!(vm.stack, [5, 8]);
+    }
+
+    #[test]
+    fn stop_in_callback_stops_a_failing_native() {
+        let mut vm = vm_calling_back(|context| {
+            context.call(2, &[])?;
+            Ok(())
+        });
+
+        assert!(matches!(vm.run(), Ok(Status::Halted)));
+        assert!(vm.is_finished());
+        assert_eq!(vm.stack, [5, 8]);
+    }
+
+    // Procedures called from the host, main is paused between them
+    const CALLED: &str = "@CVMA 1
+@Procedura 0 \"main\" 0
+PCHNIJ 1
+PCHNIJ 2
+PCHNIJ 3
+STOP
+WRÓĆ
+
+@Procedura 1 \"dodaj\" 2
+ZMIENNA.K 0
+ZMIENNA.K 1
+DODAJ.C
+ZMIENNA.U 0
+WRÓĆ
+
+@Procedura 2 \"zatrzymaj\" 0
+PCHNIJ 8
+STOP
+WRÓĆ
+
+@Procedura 3 \"dziel\" 0
+WYWOŁAJ 4
+WRÓĆ
+
+@Procedura 4 \"przez zero\" 0
+PCHNIJ 1
+PCHNIJ 0
+DZIEL.C
+WRÓĆ
+";
+
+    fn vm_for_calls() -> Vm {
+        let cvma_file = crate::parse::read_from_string(CALLED);
+        assert!(cvma_file.errors.is_empty());
+
+        Vm::new(cvma_file.procedures)
+    }
+
+    #[test]
+    fn call_checks_the_argument_count() {
+        let mut vm = vm_for_calls();
+
+        assert_eq!(vm.call(1, &[2, 3]).unwrap(), Some(5));
+        assert_eq!(vm.call_by_name("dodaj", &[4, 5]).unwrap(), Some(9));
+
+        let error = vm.call(1, &[2]).unwrap_err();
+        assert_eq!(
+            error.kind.get_message(),
+            "procedure 1 takes 2 arguments but 1 were given"
+        );
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn stop_in_a_called_procedure_is_an_error() {
+        let mut vm = vm_for_calls();
+        vm.stack.push_back(4);
+
+        let error = vm.call_by_name("zatrzymaj", &[]).unwrap_err();
+        assert_eq!(
+            error.kind.get_message(),
+            "procedure 2 did not return: the program was stopped"
+        );
+        assert_eq!(vm.stack, [4]);
+        assert!(vm.call_stack().is_empty());
+    }
+
+    #[test]
+    fn paused_program_resumes_after_a_call() {
+        let mut vm = vm_for_calls();
+        vm.start(0).unwrap();
+        vm.step().unwrap();
+        assert!(matches!(vm.run_for(1), Ok(Status::Paused)));
+
+        assert_eq!(vm.call(1, &[6, 7]).unwrap(), Some(13));
+        assert!(vm.call(2, &[]).is_err());
+        assert_eq!(vm.stack, [1, 2]);
+        assert_eq!(vm.call_stack()[0].pc, 2);
+
+        assert!(matches!(vm.resume(), Ok(Status::Halted)));
+        assert_eq!(vm.stack, [1, 2, 3]);
+    }
+
+    #[test]
+    fn failed_call_keeps_its_stack_trace() {
+        let mut vm = vm_for_calls();
+        vm.start(0).unwrap();
+        vm.step().unwrap();
+
+        let error = vm.call(3, &[]).unwrap_err();
+        assert_eq!(error.kind.get_message(), "division by zero in DZIEL.C");
+
+        let trace: Vec<_> = vm
+            .stack_trace()
+            .iter()
+            .map(|(procedure, position)| (procedure.index, position.line))
+            .collect();
+        assert_eq!(trace, [(4, 28), (3, 22)]);
+
+        // The paused program is not affected and shows its own frames again
+        assert!(matches!(vm.step(), Ok(Status::Paused)));
+        assert_eq!(vm.stack_trace().len(), 1);
+        assert_eq!(vm.stack_trace()[0].1.line, 4);
+    }
+}
@@ -0,0 +1,150 @@
+use libkrem::hooks::Hooks;
+use libkrem::parse::{Instruction, Procedure};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+
+// Records how many times every instruction ran and how many times every
+// conditional jump was taken and not taken. Runs of the same program can be
+// recorded into one Coverage.
+#[derive(Default)]
+pub struct Coverage {
+    // (procedure index, pc) -> executions
+    hits: HashMap<(u64, u64), u64>,
+    // (procedure index, pc) -> [taken, not taken]
+    branches: HashMap<(u64, u64), [u64; 2]>,
+}
+
+fn function_name(procedure: &Procedure) -> String {
+    if procedure.name.is_empty() {
+        format!("{:X}", procedure.index)
+    } else {
+        procedure.name.clone()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // Writes an lcov record for the source file the procedures were read from.
+    // Lines are CVMA source lines, a line with several instructions gets the
+    // count of the one executed most. Every IDŹDO.ZE and IDŹDO.NZ is a branch
+    // with two outcomes, taken first.
+    pub fn write_lcov(
+        &self,
+        source: &str,
+        procedures: &VecDeque<Procedure>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        writeln!(output, "TN:")?;
+        writeln!(output, "SF:{}", source)?;
+
+        let mut functions: Vec<&Procedure> = procedures
+            .iter()
+            .filter(|procedure| !procedure.code.is_empty())
+            .collect();
+        functions.sort_by_key(|procedure| procedure.positions[0].line);
+
+        for procedure in &functions {
+            writeln!(
+                output,
+                "FN:{},{}",
+                procedure.positions[0].line,
+                function_name(procedure)
+            )?;
+        }
+
+        let mut functions_hit = 0;
+        for procedure in &functions {
+            let hits = self.hits.get(&(procedure.index, 0)).cloned().unwrap_or(0);
+            if hits > 0 {
+                functions_hit += 1;
+            }
+
+            writeln!(output, "FNDA:{},{}", hits, function_name(procedure))?;
+        }
+
+        writeln!(output, "FNF:{}", functions.len())?;
+        writeln!(output, "FNH:{}", functions_hit)?;
+
+        // line -> hits, and line -> counts of the jumps on it
+        let mut lines: BTreeMap<i32, u64> = BTreeMap::new();
+        let mut branches: BTreeMap<i32, Vec<Option<[u64; 2]>>> = BTreeMap::new();
+
+        for procedure in &functions {
+            for (pc, instruction) in procedure.code.iter().enumerate() {
+                let key = (procedure.index, pc as u64);
+                let line = procedure.positions[pc].line;
+                let hits = self.hits.get(&key).cloned().unwrap_or(0);
+
+                let line_hits = lines.entry(line).or_insert(0);
+                *line_hits = (*line_hits).max(hits);
+
+                if let Instruction::IdzDoZe(_) | Instruction::IdzDoNz(_) = instruction {
+                    branches
+                        .entry(line)
+                        .or_default()
+                        .push(self.branches.get(&key).cloned());
+                }
+            }
+        }
+
+        let (mut branches_found, mut branches_hit) = (0, 0);
+        for (line, blocks) in &branches {
+            for (block, counts) in blocks.iter().enumerate() {
+                for branch in 0..2 {
+                    branches_found += 1;
+
+                    match counts {
+                        Some(counts) => {
+                            if counts[branch] > 0 {
+                                branches_hit += 1;
+                            }
+
+                            writeln!(
+                                output,
+                                "BRDA:{},{},{},{}",
+                                line, block, branch, counts[branch]
+                            )?;
+                        }
+                        // The jump itself never ran
+                        None => writeln!(output, "BRDA:{},{},{},-", line, block, branch)?,
+                    }
+                }
+            }
+        }
+
+        writeln!(output, "BRF:{}", branches_found)?;
+        writeln!(output, "BRH:{}", branches_hit)?;
+
+        for (line, hits) in &lines {
+            writeln!(output, "DA:{},{}", line, hits)?;
+        }
+
+        writeln!(output, "LF:{}", lines.len())?;
+        writeln!(
+            output,
+            "LH:{}",
+            lines.values().filter(|&&hits| hits > 0).count()
+        )?;
+        writeln!(output, "end_of_record")
+    }
+}
+
+impl Hooks for Coverage {
+    fn before_instruction(&mut self, procedure: &Procedure, pc: u64, stack: &VecDeque<u64>) {
+        let key = (procedure.index, pc);
+        *self.hits.entry(key).or_insert(0) += 1;
+
+        // Whether the jump is taken depends on the value it pops
+        let taken = match procedure.code[pc as usize] {
+            Instruction::IdzDoZe(_) => stack.back() == Some(&0),
+            Instruction::IdzDoNz(_) => stack.back() != Some(&0),
+            _ => return,
+        };
+
+        let counts = self.branches.entry(key).or_insert([0, 0]);
+        counts[if taken { 0 } else { 1 }] += 1;
+    }
+}
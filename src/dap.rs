@@ -0,0 +1,555 @@
+use crate::debugger::format_float;
+use crate::configure_vm;
+use libkrem::natives::{Io, OutputBuffer};
+use libkrem::vm::{Status, Vm};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+// The only thread of a CVM program
+const THREAD_ID: u64 = 1;
+
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut content_length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; content_length?];
+    input.read_exact(&mut body).ok()?;
+
+    serde_json::from_slice(&body).ok()
+}
+
+// What a variablesReference handed to the client points at. References are
+// only valid while the program is stopped.
+enum Container {
+    // Value stack slots of the frame at the given depth, innermost is 0
+    Stack(usize),
+    Heap,
+    Block(u64),
+}
+
+fn format_value(value: u64) -> String {
+    format!(
+        "{} (0x{:X}, float {})",
+        value as i64,
+        value,
+        format_float(f64::from_bits(value))
+    )
+}
+
+struct Session<W: Write> {
+    output: W,
+    seq: u64,
+    program: String,
+    vm: Option<Vm>,
+    program_output: OutputBuffer,
+    stop_on_entry: bool,
+    breakpoints: HashSet<i32>,
+    containers: Vec<Container>,
+    // Set after a runtime error, the state can be inspected but not resumed
+    failed: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        self.output.flush().unwrap();
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    // Forwards what the program printed since the last call
+    fn flush_program_output(&mut self) {
+        let output = self.program_output.take_string();
+
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }));
+        }
+    }
+
+    fn launch(&mut self, request: &Value) {
+        let arguments = &request["arguments"];
+        let program = arguments["program"].as_str().unwrap_or_default().to_owned();
+
+        let content = match fs::read_to_string(&program) {
+            Ok(content) => content,
+            Err(error) => {
+                return self.respond_error(request, &format!("cannot read {}: {}", program, error))
+            }
+        };
+
+        let cvma_file = libkrem::parse::read_from_string(&content);
+
+        if let Some(error) = cvma_file.errors.front() {
+            use libkrem::error::Info;
+
+            let message = format!(
+                "parsing error at line {}: {}",
+                error.position.line,
+                error.get_message()
+            );
+            return self.respond_error(request, &message);
+        }
+
+        // The program cannot read stdin, it carries the protocol
+        let flags: Vec<String> = arguments["flags"]
+            .as_array()
+            .map(|flags| {
+                flags
+                    .iter()
+                    .filter_map(|flag| flag.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let io = Io {
+            input: Box::new(io::empty()),
+            output: Box::new(self.program_output.clone()),
+        };
+        // Stdout carries the protocol, so invalid flags are reported in the
+        // response
+        let vm_builder = match configure_vm(Vm::builder(cvma_file.procedures), &flags) {
+            Ok(vm_builder) => vm_builder,
+            Err(error) => return self.respond_error(request, &error),
+        };
+        let mut vm = vm_builder.io(io).build();
+
+        if let Err(error) = vm.start(0) {
+            return self.respond_error(request, &error.kind.get_message());
+        }
+
+        self.program = program;
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.vm = Some(vm);
+        self.respond(request, json!({}));
+
+        // Breakpoints are accepted once the program is loaded
+        self.event("initialized", json!({}));
+    }
+
+    // Lines of the program that hold an instruction
+    fn instruction_lines(&self) -> HashSet<i32> {
+        self.vm
+            .iter()
+            .flat_map(|vm| vm.procedures())
+            .flat_map(|procedure| procedure.positions.iter())
+            .map(|position| position.line)
+            .collect()
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) {
+        let lines: Vec<i32> = request["arguments"]["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_i64())
+                    .map(|line| line as i32)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let instruction_lines = self.instruction_lines();
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| json!({ "verified": instruction_lines.contains(line), "line": line }))
+            .collect();
+
+        self.breakpoints = lines.into_iter().collect();
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn current_line(&self) -> Option<i32> {
+        let vm = self.vm.as_ref()?;
+        let frame = vm.call_stack().last()?;
+        let procedure = &vm.procedures()[frame.procedure];
+
+        procedure
+            .positions
+            .get(frame.pc as usize)
+            .map(|position| position.line)
+    }
+
+    // Steps while `keep_going` holds, stopping early on breakpoints, the end of
+    // the program and errors. Sends the matching stopped or terminated event.
+    fn run_while(&mut self, reason: &str, keep_going: impl Fn(&Vm) -> bool) {
+        self.containers.clear();
+
+        if self.failed {
+            self.finish();
+            return;
+        }
+
+        let vm = match &mut self.vm {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        let mut reason = reason;
+        let mut description = None;
+
+        loop {
+            match vm.step() {
+                Ok(Status::Paused) => {}
+                Ok(Status::Halted) => {
+                    self.flush_program_output();
+                    self.finish();
+                    return;
+                }
+                Ok(Status::OutOfFuel) => {
+                    reason = "pause";
+                    description = Some("out of fuel".to_owned());
+                    break;
+                }
+                Ok(Status::WaitingForInput) => {
+                    reason = "pause";
+                    description = Some("waiting for input".to_owned());
+                    break;
+                }
+                Err(error) => {
+                    self.failed = true;
+                    reason = "exception";
+                    description = Some(format!(
+                        "{} at line {}",
+                        error.kind.get_message(),
+                        error.position.line
+                    ));
+                    break;
+                }
+            }
+
+            let frame = vm.call_stack().last().unwrap();
+            let line = vm.procedures()[frame.procedure].positions[frame.pc as usize].line;
+
+            if self.breakpoints.contains(&line) {
+                reason = "breakpoint";
+                break;
+            }
+
+            if !keep_going(vm) {
+                break;
+            }
+        }
+
+        self.flush_program_output();
+
+        if let Some(description) = &description {
+            self.event(
+                "output",
+                json!({ "category": "stderr", "output": format!("{}\n", description) }),
+            );
+        }
+
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "text": description,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    fn finish(&mut self) {
+        self.event(
+            "exited",
+            json!({ "exitCode": if self.failed { 1 } else { 0 } }),
+        );
+        self.event("terminated", json!({}));
+    }
+
+    // Starts the program after the client sent its breakpoints
+    fn configuration_done(&mut self, request: &Value) {
+        self.respond(request, json!({}));
+
+        if self.vm.is_none() {
+            return;
+        }
+
+        if self.stop_on_entry {
+            self.event(
+                "stopped",
+                json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+            );
+        } else if self
+            .current_line()
+            .is_some_and(|line| self.breakpoints.contains(&line))
+        {
+            self.event(
+                "stopped",
+                json!({ "reason": "breakpoint", "threadId": THREAD_ID, "allThreadsStopped": true }),
+            );
+        } else {
+            self.run_while("breakpoint", |_| true);
+        }
+    }
+
+    // One stack frame per active procedure call, innermost first. The frame id
+    // is its depth.
+    fn stack_trace(&mut self, request: &Value) {
+        let vm = match &self.vm {
+            Some(vm) => vm,
+            None => return self.respond(request, json!({ "stackFrames": [], "totalFrames": 0 })),
+        };
+
+        let frames: Vec<Value> = vm
+            .call_stack()
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let procedure = &vm.procedures()[frame.procedure];
+                let pc = if depth == 0 && !self.failed {
+                    frame.pc
+                } else {
+                    frame.pc.saturating_sub(1)
+                };
+                let line = procedure
+                    .positions
+                    .get(pc as usize)
+                    .map_or(0, |position| position.line);
+
+                json!({
+                    "id": depth,
+                    "name": format!("{} ({:X})", procedure.name, procedure.index),
+                    "source": { "path": self.program },
+                    "line": line,
+                    "column": 1,
+                })
+            })
+            .collect();
+
+        let total = frames.len();
+        self.respond(
+            request,
+            json!({ "stackFrames": frames, "totalFrames": total }),
+        );
+    }
+
+    fn add_container(&mut self, container: Container) -> usize {
+        self.containers.push(container);
+        self.containers.len()
+    }
+
+    fn scopes(&mut self, request: &Value) {
+        let depth = request["arguments"]["frameId"].as_u64().unwrap_or(0) as usize;
+        let stack = self.add_container(Container::Stack(depth));
+        let heap = self.add_container(Container::Heap);
+
+        self.respond(
+            request,
+            json!({ "scopes": [
+                { "name": "Stack", "variablesReference": stack, "expensive": false },
+                { "name": "Heap", "variablesReference": heap, "expensive": false },
+            ] }),
+        );
+    }
+
+    fn variables(&mut self, request: &Value) {
+        let reference = request["arguments"]["variablesReference"]
+            .as_u64()
+            .unwrap_or(0) as usize;
+        let container = match reference
+            .checked_sub(1)
+            .and_then(|index| self.containers.get(index))
+        {
+            Some(Container::Stack(depth)) => Container::Stack(*depth),
+            Some(Container::Heap) => Container::Heap,
+            Some(Container::Block(handle)) => Container::Block(*handle),
+            None => return self.respond(request, json!({ "variables": [] })),
+        };
+        let vm = self.vm.as_ref().unwrap();
+
+        let variables: Vec<Value> = match container {
+            // Slots from Bottom of the frame up to Bottom of the frame it
+            // called, named like the operand of ZMIENNA.K
+            Container::Stack(depth) => {
+                let frames = vm.call_stack();
+                let index = frames.len().saturating_sub(depth + 1);
+                let bottom = frames.get(index).map_or(0, |frame| frame.bottom as usize);
+                let top = frames
+                    .get(index + 1)
+                    .map_or(vm.stack.len(), |frame| frame.bottom as usize);
+
+                vm.stack
+                    .iter()
+                    .enumerate()
+                    .take(top)
+                    .skip(bottom)
+                    .map(|(position, &value)| {
+                        json!({
+                            "name": format!("{}", position - bottom),
+                            "value": format_value(value),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect()
+            }
+            Container::Heap => {
+                let blocks: Vec<(u64, usize)> = vm
+                    .allocation_array
+                    .iter()
+                    .map(|(handle, block)| (handle, block.len()))
+                    .collect();
+
+                blocks
+                    .into_iter()
+                    .map(|(handle, size)| {
+                        let reference = self.add_container(Container::Block(handle));
+
+                        json!({
+                            "name": format!("{:X}", handle),
+                            "value": format!("{} values", size),
+                            "variablesReference": reference,
+                            "indexedVariables": size,
+                        })
+                    })
+                    .collect()
+            }
+            Container::Block(handle) => match vm.allocation_array.get(handle) {
+                Ok(block) => block
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &value)| {
+                        json!({
+                            "name": format!("{}", index),
+                            "value": format_value(value),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+        };
+
+        self.respond(request, json!({ "variables": variables }));
+    }
+
+    // Returns false when the session should end.
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or_default();
+
+        match command {
+            "initialize" => {
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsTerminateRequest": true,
+                    }),
+                );
+            }
+            "launch" => self.launch(request),
+            "setBreakpoints" => self.set_breakpoints(request),
+            "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] })),
+            "configurationDone" => self.configuration_done(request),
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            ),
+            "stackTrace" => self.stack_trace(request),
+            "scopes" => self.scopes(request),
+            "variables" => self.variables(request),
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }));
+                self.run_while("breakpoint", |_| true);
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, json!({}));
+
+                let depth = self.vm.as_ref().map_or(0, |vm| vm.call_stack().len());
+                match command {
+                    "next" => self.run_while("step", |vm| vm.call_stack().len() > depth),
+                    "stepOut" => self.run_while("step", |vm| vm.call_stack().len() >= depth),
+                    _ => self.run_while("step", |_| false),
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}));
+
+                if command == "terminate" {
+                    self.event("terminated", json!({}));
+                }
+
+                return command != "disconnect";
+            }
+            _ => self.respond_error(request, &format!("unsupported request {}", command)),
+        }
+
+        true
+    }
+}
+
+// Serves the Debug Adapter Protocol over stdin and stdout until the client
+// disconnects.
+pub fn run() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    let mut session = Session {
+        output: io::stdout(),
+        seq: 0,
+        program: String::new(),
+        vm: None,
+        program_output: OutputBuffer::default(),
+        stop_on_entry: false,
+        breakpoints: HashSet::new(),
+        containers: Vec::new(),
+        failed: false,
+    };
+
+    while let Some(request) = read_message(&mut input) {
+        if !session.handle(&request) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,583 @@
+use libkrem::error::Position;
+use libkrem::parse::{self, Procedure};
+use libkrem::snapshot;
+use libkrem::vm::{Checkpoint, RuntimeError, Status, Vm};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+
+// Instructions executed between checkpoints. Going back restores the closest
+// checkpoint and executes the rest again, so this bounds the work of a reverse
+// step at the cost of the memory for a copy of the state.
+const CHECKPOINT_INTERVAL: u64 = 1000;
+
+const HELP: &str = "Commands:
+  step, s              execute one instruction
+  next, n              execute one instruction, stepping over WYWOŁAJ
+  finish, f            run until the current procedure returns
+  continue, c          run until a breakpoint or the end of the program
+  reverse-step, rs     go back one instruction
+  reverse-continue, rc go back to the previous breakpoint or the start
+  break, b LOCATION    set a breakpoint, LOCATION is a line number, a procedure
+                       name or an address procedure:pc (hex, d for dec)
+  delete, d N          delete breakpoint number N
+  breakpoints          list breakpoints
+  stack                show the value stack relative to Bottom
+  heap [BLOCK]         list memory blocks or show the values of one (hex)
+  backtrace, bt        show the call stack
+  list, l              show the source around the current instruction
+  snapshot FILE        save the state of the program to resume it later
+  quit, q              stop debugging
+An empty line repeats the previous command.";
+
+enum Breakpoint {
+    Line(i32),
+    // Stops on the first instruction of the procedure
+    Procedure(String),
+    // Procedure index and instruction address, the closest thing to a label
+    // CVMA has since jumps target addresses
+    Address(u64, u64),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Line(line) => write!(f, "line {}", line),
+            Breakpoint::Procedure(name) => write!(f, "procedure \"{}\"", name),
+            Breakpoint::Address(index, pc) => write!(f, "address {:X}:{:X}", index, pc),
+        }
+    }
+}
+
+// A procedure on the call stack together with the instruction it is at
+struct Location<'a> {
+    procedure: &'a Procedure,
+    pc: u64,
+    position: Position,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:X}) pc {:X}, line {}",
+            self.procedure.name, self.procedure.index, self.pc, self.position.line
+        )
+    }
+}
+
+fn parse_number(string: &str) -> Option<u64> {
+    let mut errors = VecDeque::new();
+    let position = Position { line: 0, column: 0 };
+    let number = parse::get_number_from_string(string, false, &position, &mut errors);
+
+    if errors.is_empty() {
+        Some(number)
+    } else {
+        None
+    }
+}
+
+// Plain notation for ordinary magnitudes, scientific for the rest, so that
+// integers read as floats stay short
+pub fn format_float(float: f64) -> String {
+    if float == 0.0 || !float.is_finite() || (1e-6..1e16).contains(&float.abs()) {
+        format!("{}", float)
+    } else {
+        format!("{:e}", float)
+    }
+}
+
+// The value shown as a signed integer, a float and a character
+fn format_value(value: u64) -> String {
+    let float = format_float(f64::from_bits(value));
+
+    let character = match char::from_u32(value as u32) {
+        Some(character) if value <= u32::MAX as u64 && !character.is_control() => character,
+        _ => '.',
+    };
+
+    format!("{:>20}  {:<24}  {}", value as i64, float, character)
+}
+
+struct Debugger<'a> {
+    vm: Vm,
+    path: &'a str,
+    content: &'a str,
+    lines: Vec<&'a str>,
+    breakpoints: Vec<Breakpoint>,
+    // Set after a runtime error, the state can be inspected but not resumed
+    failed: bool,
+    // Instructions executed since the start of the program
+    steps: u64,
+    // Checkpoints by the number of executed instructions, in order
+    checkpoints: Vec<(u64, Checkpoint)>,
+}
+
+impl Debugger<'_> {
+    // Call stack innermost first. The innermost procedure is at the instruction
+    // executed next, the callers at their WYWOŁAJ.
+    fn locations(&self) -> Vec<Location<'_>> {
+        let procedures = self.vm.procedures();
+        let frames = self.vm.call_stack();
+
+        frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let procedure = &procedures[frame.procedure];
+                let pc = if depth == 0 && !self.failed {
+                    frame.pc
+                } else {
+                    frame.pc.saturating_sub(1)
+                };
+                let position = procedure
+                    .positions
+                    .get(pc as usize)
+                    .cloned()
+                    .unwrap_or(Position { line: 0, column: 0 });
+
+                Location {
+                    procedure,
+                    pc,
+                    position,
+                }
+            })
+            .collect()
+    }
+
+    fn breakpoint_hit(&self) -> Option<usize> {
+        let locations = self.locations();
+        let location = locations.first()?;
+
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Breakpoint::Line(line) => location.position.line == *line,
+                Breakpoint::Procedure(name) => location.procedure.name == *name && location.pc == 0,
+                Breakpoint::Address(index, pc) => {
+                    location.procedure.index == *index && location.pc == *pc
+                }
+            })
+    }
+
+    fn print_location(&self) {
+        if let Some(location) = self.locations().first() {
+            let line = location.position.line as usize;
+            let source = self.lines.get(line.wrapping_sub(1)).unwrap_or(&"");
+
+            println!("{}: {}", location, source.trim());
+        }
+    }
+
+    // Steps while `keep_going` holds, stopping early on breakpoints, the end of
+    // the program and errors.
+    fn run_while(&mut self, keep_going: impl Fn(&Vm) -> bool) {
+        if self.failed || self.vm.is_finished() {
+            println!("The program is not running");
+            return;
+        }
+
+        loop {
+            match self.step() {
+                Ok(Status::Paused) => {}
+                Ok(Status::Halted) => {
+                    println!("The program halted");
+                    return;
+                }
+                Ok(Status::OutOfFuel) => {
+                    println!(
+                        "Out of fuel after consuming {} units",
+                        self.vm.fuel_consumed()
+                    );
+                    break;
+                }
+                Ok(Status::WaitingForInput) => {
+                    println!("The program is waiting for input");
+                    break;
+                }
+                Err(error) => {
+                    self.failed = true;
+                    libkrem::error_print::print_error(
+                        "runtime error",
+                        self.path,
+                        self.content,
+                        &error.position,
+                        &error.kind.get_message(),
+                        error.kind.get_suggestion(),
+                    );
+                    self.print_backtrace();
+                    return;
+                }
+            }
+
+            if let Some(number) = self.breakpoint_hit() {
+                println!("Breakpoint {}, {}", number + 1, self.breakpoints[number]);
+                break;
+            }
+
+            if !keep_going(&self.vm) {
+                break;
+            }
+        }
+
+        self.print_location();
+    }
+
+    // Executes one instruction, keeping count of them and taking checkpoints.
+    fn step(&mut self) -> Result<Status, RuntimeError> {
+        let status = self.vm.step()?;
+
+        // Nothing was executed if the program could not continue
+        if let Status::Paused | Status::Halted = status {
+            self.steps += 1;
+
+            let last_checkpoint = self.checkpoints.last().map_or(0, |(steps, _)| *steps);
+            if self.steps >= last_checkpoint + CHECKPOINT_INTERVAL {
+                self.checkpoints.push((self.steps, self.vm.checkpoint()));
+            }
+        }
+
+        Ok(status)
+    }
+
+    // Goes back to the state after `target` instructions by restoring the
+    // closest checkpoint before it and executing the rest again. The output of
+    // the program is not written again.
+    fn go_to(&mut self, target: u64) {
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .rposition(|(steps, _)| *steps <= target)
+            .expect("the first checkpoint is taken at the start");
+
+        self.execute_from(checkpoint, target, |_| {});
+    }
+
+    // Restores the checkpoint with the given index and executes up to `target`
+    // instructions, calling `visit` before every one of them.
+    fn execute_from(&mut self, checkpoint: usize, target: u64, mut visit: impl FnMut(&Self)) {
+        let (steps, checkpoint) = &self.checkpoints[checkpoint];
+
+        self.vm.restore(checkpoint);
+        self.steps = *steps;
+        self.failed = false;
+
+        let output = mem::replace(&mut self.vm.io_mut().output, Box::new(io::sink()));
+
+        while self.steps < target {
+            visit(self);
+
+            // The instructions executed fine the first time
+            self.vm
+                .step()
+                .expect("executing recorded instructions again");
+            self.steps += 1;
+        }
+
+        self.vm.io_mut().output = output;
+    }
+
+    // The instruction that failed or would run next has not been executed, so
+    // going back one instruction leads to the state before the last executed
+    // one.
+    fn reverse_step(&mut self) {
+        if self.steps == 0 {
+            println!("Already at the start of the program");
+            return;
+        }
+
+        self.go_to(self.steps - 1);
+        self.print_location();
+    }
+
+    // Looks for the last state with a breakpoint hit before the current one,
+    // executing the instructions again one checkpoint interval at a time from
+    // the latest one.
+    fn reverse_continue(&mut self) {
+        if self.steps == 0 {
+            println!("Already at the start of the program");
+            return;
+        }
+
+        let mut end = self.steps;
+        let mut checkpoint = self.checkpoints.iter().rposition(|(steps, _)| *steps < end);
+
+        while let Some(index) = checkpoint {
+            let mut hit = None;
+
+            self.execute_from(index, end, |debugger| {
+                if let Some(number) = debugger.breakpoint_hit() {
+                    hit = Some((debugger.steps, number));
+                }
+            });
+
+            if let Some((steps, number)) = hit {
+                self.go_to(steps);
+                println!("Breakpoint {}, {}", number + 1, self.breakpoints[number]);
+                self.print_location();
+                return;
+            }
+
+            end = self.checkpoints[index].0;
+            checkpoint = index.checked_sub(1);
+        }
+
+        self.go_to(0);
+        println!("Reached the start of the program");
+        self.print_location();
+    }
+
+    fn add_breakpoint(&mut self, location: &str) {
+        let location = location.trim().trim_matches('"');
+
+        let breakpoint = if let Ok(line) = location.parse::<i32>() {
+            Breakpoint::Line(line)
+        } else if let Some((index, pc)) = location.split_once(':') {
+            match (parse_number(index), parse_number(pc)) {
+                (Some(index), Some(pc)) => Breakpoint::Address(index, pc),
+                _ => {
+                    println!("Invalid address {}", location);
+                    return;
+                }
+            }
+        } else if self
+            .vm
+            .procedures()
+            .iter()
+            .any(|procedure| procedure.name == location)
+        {
+            Breakpoint::Procedure(location.to_owned())
+        } else {
+            println!("No line, address or procedure named {}", location);
+            return;
+        };
+
+        println!("Breakpoint {}, {}", self.breakpoints.len() + 1, breakpoint);
+        self.breakpoints.push(breakpoint);
+    }
+
+    fn delete_breakpoint(&mut self, number: &str) {
+        match number.trim().parse::<usize>() {
+            Ok(number) if number >= 1 && number <= self.breakpoints.len() => {
+                self.breakpoints.remove(number - 1);
+            }
+            _ => println!("No breakpoint number {}", number.trim()),
+        }
+    }
+
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("No breakpoints");
+        }
+
+        for (number, breakpoint) in self.breakpoints.iter().enumerate() {
+            println!("{}: {}", number + 1, breakpoint);
+        }
+    }
+
+    // Values are indexed relative to Bottom of the current procedure, like the
+    // operand of ZMIENNA.K. Values of callers have negative indices.
+    fn print_stack(&self) {
+        let bottom = self
+            .vm
+            .call_stack()
+            .last()
+            .map_or(0, |frame| frame.bottom as i64);
+
+        if self.vm.stack.is_empty() {
+            println!("The value stack is empty");
+        }
+
+        for (position, &value) in self.vm.stack.iter().enumerate() {
+            println!("{:>5}  {}", position as i64 - bottom, format_value(value));
+        }
+    }
+
+    fn print_heap(&self, block: &str) {
+        if block.trim().is_empty() {
+            let mut empty = true;
+
+            for (handle, values) in self.vm.allocation_array.iter() {
+                println!("{:X}: {} values", handle, values.len());
+                empty = false;
+            }
+
+            if empty {
+                println!("No memory blocks are allocated");
+            }
+
+            return;
+        }
+
+        let handle = match parse_number(block.trim()) {
+            Some(handle) => handle,
+            None => {
+                println!("Invalid memory block {}", block.trim());
+                return;
+            }
+        };
+
+        match self.vm.allocation_array.get(handle) {
+            Ok(values) => {
+                for (index, &value) in values.iter().enumerate() {
+                    println!("{:>5}  {}", index, format_value(value));
+                }
+            }
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    fn print_backtrace(&self) {
+        for (depth, location) in self.locations().iter().enumerate() {
+            println!("#{} {}", depth, location);
+        }
+    }
+
+    fn print_source(&self) {
+        let current = match self.locations().first() {
+            Some(location) => location.position.line,
+            None => {
+                println!("The program is not running");
+                return;
+            }
+        };
+
+        let first = (current - 5).max(1);
+        let last = (current + 5).min(self.lines.len() as i32);
+
+        for line in first..=last {
+            let marker = if line == current { "=>" } else { "  " };
+            let breakpoint = self.breakpoints.iter().any(|breakpoint| {
+                matches!(breakpoint, Breakpoint::Line(breakpoint_line) if *breakpoint_line == line)
+            });
+
+            println!(
+                "{}{} {:>4} {}",
+                marker,
+                if breakpoint { "*" } else { " " },
+                line,
+                self.lines[line as usize - 1]
+            );
+        }
+    }
+
+    fn save_snapshot(&self, path: &str) {
+        let path = path.trim();
+
+        if path.is_empty() {
+            println!("snapshot expects a file");
+            return;
+        }
+
+        if self.failed {
+            println!("Error: {}", snapshot::FAILED);
+            return;
+        }
+
+        let written = fs::File::create(path).and_then(|file| {
+            let mut output = io::BufWriter::new(file);
+            snapshot::write(&self.vm, &mut output)?;
+            output.flush()
+        });
+
+        match written {
+            Ok(()) => println!("Saved the state to {}", path),
+            Err(error) => println!("Error: {}: {}", path, error),
+        }
+    }
+
+    // Returns false when the session should end.
+    fn execute_command(&mut self, command: &str) -> bool {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+
+        match name {
+            "step" | "s" => self.run_while(|_| false),
+            "next" | "n" => {
+                let depth = self.vm.call_stack().len();
+                self.run_while(|vm| vm.call_stack().len() > depth);
+            }
+            "finish" | "f" => {
+                let depth = self.vm.call_stack().len();
+                self.run_while(|vm| vm.call_stack().len() >= depth);
+            }
+            "continue" | "c" => self.run_while(|_| true),
+            "reverse-step" | "rs" => self.reverse_step(),
+            "reverse-continue" | "rc" => self.reverse_continue(),
+            "break" | "b" => self.add_breakpoint(argument),
+            "delete" | "d" => self.delete_breakpoint(argument),
+            "breakpoints" => self.print_breakpoints(),
+            "stack" => self.print_stack(),
+            "heap" => self.print_heap(argument),
+            "backtrace" | "bt" => self.print_backtrace(),
+            "list" | "l" => self.print_source(),
+            "snapshot" => self.save_snapshot(argument),
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return false,
+            _ => println!(
+                "Unknown command {}, type help for the list of commands",
+                name
+            ),
+        }
+
+        true
+    }
+}
+
+// Runs the main procedure under an interactive debugger reading commands from
+// stdin. The program starts paused on its first instruction, or where it was
+// stopped if the VM was resumed from a snapshot.
+pub fn run(mut vm: Vm, path: &str, content: &str) {
+    if vm.is_finished() {
+        if let Err(error) = vm.start(0) {
+            println!("Error: {}", error.kind.get_message());
+            return;
+        }
+    }
+
+    let mut debugger = Debugger {
+        vm,
+        path,
+        content,
+        lines: content.lines().collect(),
+        breakpoints: Vec::new(),
+        failed: false,
+        steps: 0,
+        checkpoints: Vec::new(),
+    };
+
+    debugger.checkpoints.push((0, debugger.vm.checkpoint()));
+
+    println!("Debugging {}, type help for the list of commands", path);
+    debugger.print_location();
+
+    let mut previous_command = String::new();
+
+    loop {
+        print!("(kdb) ");
+        io::stdout().flush().unwrap();
+
+        let mut command = String::new();
+        if io::stdin().read_line(&mut command).unwrap_or(0) == 0 {
+            break;
+        }
+
+        let command = match command.trim() {
+            "" => previous_command.clone(),
+            command => command.to_owned(),
+        };
+
+        if command.is_empty() {
+            continue;
+        }
+
+        if !debugger.execute_command(&command) {
+            break;
+        }
+
+        previous_command = command;
+    }
+}
@@ -1,516 +1,313 @@
-use libkrem::parse::Instruction;
-use std::collections::HashMap;
-use std::collections::VecDeque;
+use libkrem::fuel::CostTable;
+use libkrem::replay::{self, Recording};
+use libkrem::snapshot;
+use libkrem::vm::{Limits, Status, Trace, Vm, VmBuilder};
 use std::env;
 use std::fs;
+use std::io;
 use std::process::exit;
 
-#[repr(u64)]
-enum ReservedNativeProcedures {
-    // 0x - I/O
-    PutC = 0x00,
-    PutZ = 0x01,
-    PutU = 0x02,
-    GetC = 0x03,
-    GetZ = 0x04,
-    GetU = 0x05,
-
-    // 1x - memory
-    Alloc = 0x10,
-    Free = 0x11,
-    Read = 0x12,
-    Write = 0x13,
-
-    // 2x - strings
-    Print = 0x20,
-}
-
-#[derive(Clone, Debug)]
-struct Procedure {
-    index: u64,
-    name: String,
-    parameter_count: u64,
-    code: Vec<Instruction>,
-}
+mod coverage;
+mod dap;
+mod debugger;
+mod profiler;
+mod test_runner;
 
-type NativeProceduresMap = HashMap<u64, Box<dyn Fn(&mut VecDeque<u64>, &mut VecDeque<Vec<u64>>)>>;
-
-macro_rules! cvm_arithmetics_u64 {
-    ($stack:expr, $op:tt) => {
-        unsafe {
-            let y = std::mem::transmute::<u64, i64>($stack.pop_back().unwrap());
-            let x = std::mem::transmute::<u64, i64>($stack.pop_back().unwrap());
-            $stack.push_back(std::mem::transmute::<i64, u64>(x $op y));
-        }
-        ()
+fn get_flag_value(args: &[String], flag: &str) -> Result<Option<u64>, String> {
+    let position = match args.iter().position(|arg| arg == flag) {
+        Some(position) => position,
+        None => return Ok(None),
     };
+
+    match args.get(position + 1).map(|value| value.parse::<u64>()) {
+        Some(Ok(value)) => Ok(Some(value)),
+        _ => Err(format!("{} expects a number", flag)),
+    }
 }
 
-macro_rules! cvm_arithmetics_f64 {
-    ($stack:expr, $op:tt) => {
-        let y = f64::from_bits($stack.pop_back().unwrap());
-        let x = f64::from_bits($stack.pop_back().unwrap());
-        $stack.push_back((x $op y).to_bits());
-        ()
+fn get_flag_path(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    let position = match args.iter().position(|arg| arg == flag) {
+        Some(position) => position,
+        None => return Ok(None),
     };
+
+    match args.get(position + 1) {
+        Some(path) => Ok(Some(path.clone())),
+        None => Err(format!("{} expects a file", flag)),
+    }
 }
 
-fn execute_procedure(
-    procedure: &libkrem::parse::Procedure,
-    procedures: &VecDeque<libkrem::parse::Procedure>,
-    stack: &mut VecDeque<u64>,
-    bottom: u64,
-    native_procedures: &NativeProceduresMap,
-    allocation_array: &mut VecDeque<Vec<u64>>,
-) {
-    let mut pc: u64 = 0;
-    let mut continue_execution = true;
-    let bottom = bottom;
-
-    while continue_execution {
-        let instruction = procedure.code[pc as usize];
-        pc += 1;
-
-        match instruction {
-            // Stack
-            Instruction::Pchnij(value) => {
-                stack.push_back(value);
-            }
-            Instruction::Usun => drop(stack.pop_back()),
-            Instruction::ZmiennaK(index) => {
-                println!("{:?}, {}", stack, bottom);
-                stack.push_back(stack[(bottom + index) as usize]);
-            }
-            Instruction::ZmiennaU(index) => {
-                let x = stack.pop_back().unwrap();
-                stack[(bottom + index) as usize] = x;
-            }
+// Prints the error of a command line flag and exits.
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => {
+            println!("Error: {}", error);
+            exit(1);
+        }
+    }
+}
 
-            // Arithemtics
-            Instruction::DodajC => {
-                cvm_arithmetics_u64!(stack, +);
-            }
-            Instruction::DodajZ => {
-                cvm_arithmetics_f64!(stack, +);
-            }
+// Creates the file and fills it with `write`, exits on errors.
+fn write_file(path: &str, write: impl FnOnce(&mut dyn io::Write) -> io::Result<()>) {
+    let written = fs::File::create(path).and_then(|file| {
+        let mut output = io::BufWriter::new(file);
+        write(&mut output)?;
+        io::Write::flush(&mut output)
+    });
 
-            Instruction::OdejmC => {
-                cvm_arithmetics_u64!(stack, -);
-            }
-            Instruction::OdejmZ => {
-                cvm_arithmetics_f64!(stack, -);
-            }
+    if let Err(error) = written {
+        println!("Error: {}: {}", path, error);
+        exit(1);
+    }
+}
 
-            Instruction::MnozC => {
-                cvm_arithmetics_u64!(stack, *);
-            }
-            Instruction::MnozZ => {
-                cvm_arithmetics_f64!(stack, *);
-            }
+// Applies the VM flags, shared by running a file, by the `; flags:`
+// directive of tests and by launch requests of the debug adapter, so errors
+// are returned instead of exiting.
+fn configure_vm(vm_builder: VmBuilder, args: &[String]) -> Result<VmBuilder, String> {
+    let limits = Limits {
+        max_heap_words: get_flag_value(args, "--max-heap")?,
+        max_stack_length: get_flag_value(args, "--max-stack")?.map(|value| value as usize),
+        max_call_depth: get_flag_value(args, "--max-call-depth")?.map(|value| value as usize),
+    };
 
-            Instruction::DzielC => {
-                cvm_arithmetics_u64!(stack, /);
-            }
-            Instruction::DzielZ => {
-                cvm_arithmetics_f64!(stack, /);
-            }
+    let cost_table = match get_flag_path(args, "--cost-table")? {
+        Some(path) => fs::read_to_string(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|content| CostTable::read_from_string(&content))
+            .map_err(|error| format!("{}: {}", path, error))?,
+        None => CostTable::new(),
+    };
 
-            Instruction::ResztaC => {
-                cvm_arithmetics_u64!(stack, %);
-            }
-            Instruction::ResztaZ => {
-                cvm_arithmetics_f64!(stack, %);
-            }
+    let mut vm_builder = vm_builder
+        .limits(limits)
+        .cost_table(cost_table)
+        .checked_arithmetic(args.contains(&"--checked-arithmetic".to_owned()));
 
-            Instruction::JakoCZ => unsafe {
-                let num = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
+    if let Some(fuel) = get_flag_value(args, "--fuel")? {
+        vm_builder = vm_builder.fuel(fuel);
+    }
 
-                stack.push_back((num as f64).to_bits());
+    if let Some(path) = get_flag_path(args, "--trace")? {
+        let sink: Box<dyn io::Write> = match path.as_str() {
+            "-" => Box::new(io::stderr()),
+            path => match fs::File::create(path) {
+                Ok(file) => Box::new(io::BufWriter::new(file)),
+                Err(error) => return Err(format!("{}: {}", path, error)),
             },
-            Instruction::JakoZC => {
-                let num = stack.pop_back().unwrap();
+        };
 
-                stack.push_back(f64::from_bits(num).floor() as u64);
-            }
+        vm_builder = vm_builder.trace(Trace {
+            sink,
+            stack_values: get_flag_value(args, "--trace-values")?.unwrap_or(4) as usize,
+        });
+    }
 
-            // Comparisons
-            Instruction::NieL => unsafe {
-                let x = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                if x == 0 {
-                    stack.push_back(1);
-                } else {
-                    stack.push_back(0);
-                }
-            },
-            Instruction::Rowne => unsafe {
-                let y = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                let x = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                stack.push_back((x == y) as u64);
-            },
-            Instruction::RowneZ => {
-                let y = f64::from_bits(stack.pop_back().unwrap());
-                let x = f64::from_bits(stack.pop_back().unwrap());
-                stack.push_back((x == y) as u64);
-            }
+    if let Some(path) = get_flag_path(args, "--record")? {
+        match fs::File::create(&path) {
+            Ok(file) => vm_builder = vm_builder.recording(Recording::Record(Box::new(file))),
+            Err(error) => return Err(format!("{}: {}", path, error)),
+        }
+    }
 
-            Instruction::MniejC => unsafe {
-                let y = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                let x = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                stack.push_back((x < y) as u64);
-            },
-            Instruction::MniejZ => {
-                let y = stack.pop_back().unwrap();
-                let x = stack.pop_back().unwrap();
-                stack.push_back((x < y) as u64);
-            }
+    if let Some(path) = get_flag_path(args, "--replay")? {
+        let calls = fs::read_to_string(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|content| replay::read_from_string(&content))
+            .map_err(|error| format!("{}: {}", path, error))?;
 
-            Instruction::MNrowC => unsafe {
-                let y = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                let x = std::mem::transmute::<u64, i64>(stack.pop_back().unwrap());
-                stack.push_back((x <= y) as u64);
-            },
-            Instruction::MNrowZ => {
-                let y = f64::from_bits(stack.pop_back().unwrap());
-                let x = f64::from_bits(stack.pop_back().unwrap());
-                stack.push_back((x <= y) as u64);
-            }
+        vm_builder = vm_builder.recording(Recording::Replay { calls, position: 0 });
+    }
 
-            // Bitwise operations
-            Instruction::NieB => {
-                let x = stack.pop_back().unwrap();
-                stack.push_back(!x);
-            }
-            Instruction::I => {
-                let y = stack.pop_back().unwrap();
-                let x = stack.pop_back().unwrap();
-                stack.push_back(x & y);
-            }
-            Instruction::Lub => {
-                let y = stack.pop_back().unwrap();
-                let x = stack.pop_back().unwrap();
-                stack.push_back(x | y);
-            }
-            Instruction::XLub => {
-                let y = stack.pop_back().unwrap();
-                let x = stack.pop_back().unwrap();
-                stack.push_back(x ^ y);
-            }
-            Instruction::PrzesunL => {
-                let y = stack.pop_back().unwrap();
-                let x = stack.pop_back().unwrap();
-                stack.push_back(x << y);
-            }
-            Instruction::PrzesunR => {
-                let y = stack.pop_back().unwrap();
-                let x = stack.pop_back().unwrap();
-                stack.push_back(x >> y);
-            }
+    Ok(vm_builder)
+}
 
-            // PC register manipulation
-            Instruction::IdzDo(new_pc) => {
-                pc = new_pc;
-            }
-            Instruction::IdzDoZe(new_pc) => {
-                let x = stack.pop_back().unwrap();
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let mut flag_show_dbg = false;
+    let mut flag_heap_report = false;
 
-                if x == 0 {
-                    pc = new_pc;
-                }
-            }
-            Instruction::IdzDoNz(new_pc) => {
-                let x = stack.pop_back().unwrap();
+    if args.get(1).map(String::as_str) == Some("test") {
+        exit(if test_runner::run(&args[2..]) { 0 } else { 1 });
+    }
 
-                if x != 0 {
-                    pc = new_pc;
-                }
-            }
-            Instruction::Wywolaj(proc_idx) => {
-                let mut new_proc: Option<&libkrem::parse::Procedure> = None;
-
-                for procedure in procedures {
-                    if procedure.index == proc_idx {
-                        new_proc = Some(procedure);
-                    }
-                }
-
-                match new_proc {
-                    Some(new_proc) => {
-                        if new_proc.code.len() == 0 {
-                            continue;
-                        }
-
-                        execute_procedure(
-                            new_proc,
-                            procedures,
-                            stack,
-                            (stack.len() - (new_proc.parameter_count as usize)) as u64,
-                            native_procedures,
-                            allocation_array,
-                        );
-                    }
-                    None => {
-                        println!(
-                            "Runtime error: Procedure with index {} does not exist",
-                            proc_idx
-                        );
-                        exit(1);
-                    }
-                }
-            }
-            Instruction::Wroc | Instruction::Stop => {
-                continue_execution = false;
-            }
+    if args.get(1).map(String::as_str) == Some("dap") {
+        dap::run();
+        return;
+    }
 
-            // Interpreter communication
-            Instruction::Nat(nat_proc) => {
-                if !native_procedures.contains_key(&nat_proc) {
-                    println!(
-                        "Runtime error: Native procedure {:X} does not exist",
-                        nat_proc
-                    );
-                    exit(1);
-                }
-
-                native_procedures.get(&nat_proc).unwrap()(stack, allocation_array);
-            }
-            Instruction::BrakOperacji => unimplemented!(),
-        }
+    let debug = args.get(1).map(String::as_str) == Some("debug");
+    if debug {
+        args.remove(1);
     }
 
-    if procedure.index != 0 {
-        while stack.len() > (bottom + 1) as usize {
-            stack.pop_back();
-        }
+    if args.len() < 2 {
+        println!(
+            "Usage: {} file [--dbg] [--heap-report] [--max-heap words] [--max-stack values] [--max-call-depth calls] [--fuel units] [--cost-table file] [--checked-arithmetic] [--trace file|-] [--trace-values count] [--record file] [--replay file] [--save-snapshot file] [--resume file] [--profile] [--profile-folded file] [--coverage file]",
+            args[0]
+        );
+        println!("       {} debug file [flags]", args[0]);
+        println!("       {} test [--coverage file] [paths...]", args[0]);
+        println!("       {} dap", args[0]);
+        exit(1);
     }
-}
 
-macro_rules! register_native_procedure {
-    ($natprocs:expr, $idx:expr, $procedure:expr) => {
-        $natprocs.insert($idx, Box::new($procedure));
-    };
-}
+    if args.contains(&"--dbg".to_owned()) {
+        flag_show_dbg = true;
+    }
 
-fn get_stdin_input() -> String {
-    let mut buffer = String::new();
-    std::io::stdin()
-        .read_line(&mut buffer)
-        .expect("Runtime error: stdin failed");
-    buffer
-}
+    if args.contains(&"--heap-report".to_owned()) {
+        flag_heap_report = true;
+    }
 
-fn peek(stack: &VecDeque<u64>, n: usize) -> u64 {
-    *stack.get((stack.len() - 1) - n).unwrap()
-}
+    let content = fs::read_to_string(args[1].as_str()).unwrap();
+    let content = content.as_str();
 
-fn register_natproc_io(native_procedures: &mut NativeProceduresMap) {
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::PutC as u64,
-        |stack, _| {
-            print!("{}", peek(stack, 0));
-        }
-    );
+    let cvma_file = libkrem::parse::read_from_string(content);
 
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::PutZ as u64,
-        |stack, _| {
-            print!("{}", f64::from_bits(peek(stack, 0)));
-        }
-    );
+    if !cvma_file.errors.is_empty() {
+        libkrem::error_print::print_errors(
+            "parsing error",
+            args[1].as_str(),
+            content,
+            cvma_file.errors,
+        );
+        exit(1);
+    }
 
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::PutU as u64,
-        |stack, _| {
-            print!("{}", char::from_u32(peek(stack, 0) as u32).unwrap());
-        }
-    );
-
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::GetC as u64,
-        |stack, _| {
-            match get_stdin_input().trim().parse::<u64>() {
-                Ok(value) => {
-                    stack.push_back(value);
-                }
-                Err(_) => {
-                    println!("Runtime error: got invalid input, expected u64");
-                    exit(1);
-                }
-            }
-        }
-    );
-
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::GetZ as u64,
-        |stack, _| {
-            match get_stdin_input().trim().parse::<f64>() {
-                Ok(value) => {
-                    stack.push_back(value.to_bits());
-                }
-                Err(_) => {
-                    println!("Runtime error: got invalid input, expected f64");
-                    exit(1);
-                }
-            }
+    let mut vm_builder = or_exit(configure_vm(Vm::builder(cvma_file.procedures), &args));
+
+    // Going back in the debugger executes instructions again, which must not
+    // read input again
+    if debug {
+        if args.contains(&"--record".to_owned()) {
+            println!("Error: --record cannot be used with debug");
+            exit(1);
         }
-    );
 
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::GetU as u64,
-        |stack, _| {
-            let mut input = get_stdin_input();
+        if !args.contains(&"--replay".to_owned()) {
+            vm_builder = vm_builder.recording(Recording::Rewindable {
+                calls: Vec::new(),
+                position: 0,
+            });
+        }
+    }
 
-            while input.is_empty() {
-                input = get_stdin_input();
-            }
+    let mut vm = vm_builder.build();
 
-            stack.push_back(input.chars().nth(0).unwrap() as u64);
-        }
-    );
-}
-fn register_natproc_memory(native_procedures: &mut NativeProceduresMap) {
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::Alloc as u64,
-        |stack, alloc_array| {
-            let to_alloc = peek(stack, 0);
+    if !vm.has_procedure(0) {
+        println!("Error: Main procedure is not defined");
+        exit(1);
+    }
 
-            let mut blocks: Vec<u64> = Vec::new();
+    let resumed = match or_exit(get_flag_path(&args, "--resume")) {
+        Some(path) => {
+            let read = fs::read_to_string(&path)
+                .map_err(|error| error.to_string())
+                .and_then(|content| snapshot::read(&mut vm, &content));
 
-            for _ in 0..to_alloc {
-                blocks.push(0);
+            if let Err(error) = read {
+                println!("Error: {}: {}", path, error);
+                exit(1);
             }
 
-            alloc_array.push_back(blocks);
-
-            stack.push_back((alloc_array.len() - 1) as u64);
-        }
-    );
-
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::Free as u64,
-        |stack, alloc_array| {
-            let alloc_addr = peek(stack, 0);
-            alloc_array.remove(alloc_addr as usize);
+            true
         }
-    );
+        None => false,
+    };
 
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::Read as u64,
-        |stack, alloc_array| {
-            let idx = peek(stack, 0) as usize;
-            let addr = peek(stack, 1) as usize;
+    if debug {
+        debugger::run(vm, args[1].as_str(), content);
+        return;
+    }
 
-            stack.push_back(alloc_array[addr][idx]);
-        }
-    );
+    let folded_path = or_exit(get_flag_path(&args, "--profile-folded"));
+    let coverage_path = or_exit(get_flag_path(&args, "--coverage"));
 
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::Write as u64,
-        |stack, alloc_array| {
-            let value = peek(stack, 0);
-            let idx = peek(stack, 1) as usize;
-            let addr = peek(stack, 2) as usize;
+    let mut profiler = if args.contains(&"--profile".to_owned()) || folded_path.is_some() {
+        Some(profiler::Profiler::new())
+    } else {
+        None
+    };
+    let mut coverage = coverage_path.as_ref().map(|_| coverage::Coverage::new());
 
-            alloc_array[addr][idx] = value;
-        }
-    );
-}
-fn register_natproc_strings(native_procedures: &mut NativeProceduresMap) {
-    register_native_procedure!(
-        native_procedures,
-        ReservedNativeProcedures::Print as u64,
-        |stack, alloc_array| {
-            let addr = peek(stack, 0) as usize;
-            let mut string: String = String::new();
-
-            for block in alloc_array[addr].clone() {
-                let bytes = block.to_ne_bytes();
-                let tmp_str = std::str::from_utf8(&bytes).unwrap();
-
-                for ch in tmp_str.chars() {
-                    if ch == '\0' {
-                        break;
-                    }
-
-                    string.push(ch);
-                }
-            }
+    let result = match (resumed, profiler.is_some() || coverage.is_some()) {
+        (false, false) => vm.run(),
+        (false, true) => vm.run_with(&mut (&mut profiler, &mut coverage)),
+        (true, false) => vm.resume(),
+        (true, true) => vm.resume_with(&mut (&mut profiler, &mut coverage)),
+    };
 
-            print!("{}", string);
+    // Profiles and coverage are written even when the program failed
+    if let Some(profiler) = &profiler {
+        if args.contains(&"--profile".to_owned()) {
+            println!("============\n{}", profiler.report(vm.procedures()));
         }
-    );
-}
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut flag_show_dbg = false;
 
-    if args.len() < 2 {
-        println!("Usage: {} file [--dbg]", args[0]);
-        exit(1);
+        if let Some(path) = &folded_path {
+            write_file(path, |output| {
+                profiler.write_folded(vm.procedures(), output)
+            });
+        }
     }
 
-    if args.contains(&"--dbg".to_owned()) {
-        flag_show_dbg = true;
+    if let (Some(coverage), Some(path)) = (&coverage, &coverage_path) {
+        write_file(path, |output| {
+            coverage.write_lcov(&args[1], vm.procedures(), output)
+        });
     }
 
-    let mut stack: VecDeque<u64> = VecDeque::new();
-    let mut allocation_array: VecDeque<Vec<u64>> = VecDeque::new();
-
-    let mut native_procedures: NativeProceduresMap = HashMap::new();
-
-    register_natproc_io(&mut native_procedures);
-    register_natproc_memory(&mut native_procedures);
-    register_natproc_strings(&mut native_procedures);
+    // A program that stopped before its end can be resumed or inspected later
+    if let Some(path) = or_exit(get_flag_path(&args, "--save-snapshot")) {
+        match result {
+            Ok(Status::Halted) => {}
+            Ok(_) => write_file(&path, |output| snapshot::write(&vm, output)),
+            Err(_) => println!("Error: {}: {}", path, snapshot::FAILED),
+        }
+    }
 
-    let content = fs::read_to_string(args[1].as_str()).unwrap();
-    let content = content.as_str();
+    let exit_code = match result {
+        Ok(Status::Halted) | Ok(Status::Paused) => 0,
+        Ok(Status::OutOfFuel) => {
+            println!(
+                "Error: out of fuel after consuming {} units",
+                vm.fuel_consumed()
+            );
+            2
+        }
+        Ok(Status::WaitingForInput) => {
+            println!("Error: the program is waiting for input");
+            1
+        }
+        Err(error) => {
+            libkrem::error_print::print_error(
+                "runtime error",
+                args[1].as_str(),
+                content,
+                &error.position,
+                &error.kind.get_message(),
+                error.kind.get_suggestion(),
+            );
+            1
+        }
+    };
 
-    let cvma_file = libkrem::parse::read_from_string(content);
+    // Reports are printed for failed programs as well, leaks matter most there
 
-    if cvma_file.errors.len() > 0 {
-        libkrem::error_print::print_errors(
-            "parsing error",
-            args[1].as_str(),
-            content,
-            cvma_file.errors,
-        );
-        exit(1);
-    }
+    if flag_show_dbg {
+        println!("============\nValue stack: {:?}", vm.stack);
 
-    let procedures = &cvma_file.procedures;
-    let mut has_main_procedure = false;
-
-    for procedure in procedures.clone() {
-        if procedure.index == 0 {
-            has_main_procedure = true;
-            execute_procedure(
-                &procedure,
-                &procedures,
-                &mut stack,
-                0,
-                &native_procedures,
-                &mut allocation_array,
-            );
+        if vm.fuel().is_some() {
+            println!("Fuel consumed: {}", vm.fuel_consumed());
         }
     }
 
-    if !has_main_procedure {
-        println!("Error: Main procedure is not defined");
-        exit(1);
+    if flag_show_dbg || flag_heap_report {
+        println!("============\n{}", vm.heap_report());
     }
 
-    if flag_show_dbg {
-        println!("============\nValue stack: {:?}", stack);
-        println!("Allocation array: {:?}", allocation_array);
+    if exit_code != 0 {
+        exit(exit_code);
     }
 }
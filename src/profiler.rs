@@ -0,0 +1,275 @@
+use libkrem::hooks::Hooks;
+use libkrem::parse::Procedure;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+// Number of instructions shown in the hottest instructions part of the report
+const HOT_INSTRUCTIONS: usize = 10;
+
+// A node of the calling context tree, one per distinct call stack
+struct Node {
+    parent: Option<usize>,
+    // CVM index of the procedure
+    procedure: u64,
+    // Instructions executed with exactly this call stack
+    count: u64,
+    children: HashMap<u64, usize>,
+}
+
+// Counts executed instructions per procedure, per instruction and per call
+// stack, and calls of native procedures. Instruction counts are used instead
+// of time, so profiles of the same program and input are reproducible.
+#[derive(Default)]
+pub struct Profiler {
+    nodes: Vec<Node>,
+    // Node of the call stack being executed
+    current: Option<usize>,
+    calls: HashMap<u64, u64>,
+    // (procedure index, pc) -> executions
+    instructions: HashMap<(u64, u64), u64>,
+    // Native procedure index -> (name, calls)
+    natives: HashMap<u64, (&'static str, u64)>,
+}
+
+struct ProcedureProfile<'a> {
+    procedure: Option<&'a Procedure>,
+    index: u64,
+    calls: u64,
+    exclusive: u64,
+    inclusive: u64,
+}
+
+fn procedure_name(procedures: &VecDeque<Procedure>, index: u64) -> String {
+    match procedures.iter().find(|procedure| procedure.index == index) {
+        Some(procedure) if !procedure.name.is_empty() => procedure.name.clone(),
+        _ => format!("{:X}", index),
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    fn enter(&mut self, parent: Option<usize>, procedure: u64) -> usize {
+        if let Some(parent) = parent {
+            if let Some(&child) = self.nodes[parent].children.get(&procedure) {
+                return child;
+            }
+        }
+
+        self.nodes.push(Node {
+            parent,
+            procedure,
+            count: 0,
+            children: HashMap::new(),
+        });
+
+        let node = self.nodes.len() - 1;
+        if let Some(parent) = parent {
+            self.nodes[parent].children.insert(procedure, node);
+        }
+
+        node
+    }
+
+    // Procedures on the call stack of the node, outermost first
+    fn path(&self, mut node: usize) -> Vec<u64> {
+        let mut path = vec![self.nodes[node].procedure];
+
+        while let Some(parent) = self.nodes[node].parent {
+            path.push(self.nodes[parent].procedure);
+            node = parent;
+        }
+
+        path.reverse();
+        path
+    }
+
+    fn total(&self) -> u64 {
+        self.nodes.iter().map(|node| node.count).sum()
+    }
+
+    fn procedure_profiles<'a>(
+        &self,
+        procedures: &'a VecDeque<Procedure>,
+    ) -> Vec<ProcedureProfile<'a>> {
+        let mut profiles: HashMap<u64, ProcedureProfile> = HashMap::new();
+
+        for (node_index, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                continue;
+            }
+
+            // A recursive procedure is counted once per stack for its inclusive
+            // count
+            let path: HashSet<u64> = self.path(node_index).into_iter().collect();
+
+            for index in path {
+                let profile = profiles.entry(index).or_insert_with(|| ProcedureProfile {
+                    procedure: procedures.iter().find(|procedure| procedure.index == index),
+                    index,
+                    calls: self.calls.get(&index).cloned().unwrap_or(0),
+                    exclusive: 0,
+                    inclusive: 0,
+                });
+
+                profile.inclusive += node.count;
+                if index == node.procedure {
+                    profile.exclusive += node.count;
+                }
+            }
+        }
+
+        let mut profiles: Vec<ProcedureProfile> = profiles.into_values().collect();
+        profiles.sort_by(|a, b| {
+            b.exclusive
+                .cmp(&a.exclusive)
+                .then(b.inclusive.cmp(&a.inclusive))
+                .then(a.index.cmp(&b.index))
+        });
+        profiles
+    }
+
+    // Report with procedures sorted by exclusive instruction count, the hottest
+    // instructions and native procedure calls.
+    pub fn report(&self, procedures: &VecDeque<Procedure>) -> String {
+        let total = self.total();
+        let mut report = String::new();
+
+        writeln!(report, "Profile: {} instructions executed", total).unwrap();
+        writeln!(
+            report,
+            "  {:>10} {:>14} {:>14}  procedure",
+            "calls", "exclusive", "inclusive"
+        )
+        .unwrap();
+
+        for profile in self.procedure_profiles(procedures) {
+            let name = match profile.procedure {
+                Some(procedure) if !procedure.name.is_empty() => {
+                    format!("{} ({:X})", procedure.name, profile.index)
+                }
+                _ => format!("{:X}", profile.index),
+            };
+
+            writeln!(
+                report,
+                "  {:>10} {:>7} {:>5.1}% {:>7} {:>5.1}%  {}",
+                profile.calls,
+                profile.exclusive,
+                percent(profile.exclusive, total),
+                profile.inclusive,
+                percent(profile.inclusive, total),
+                name
+            )
+            .unwrap();
+        }
+
+        let mut instructions: Vec<(&(u64, u64), &u64)> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        writeln!(report, "Hottest instructions:").unwrap();
+        for (&(index, pc), &count) in instructions.iter().take(HOT_INSTRUCTIONS) {
+            let procedure = procedures.iter().find(|procedure| procedure.index == index);
+            let (mnemonic, line) = match procedure {
+                Some(procedure) => (
+                    procedure.code[pc as usize].mnemonic(),
+                    procedure.positions[pc as usize].line,
+                ),
+                None => ("?", 0),
+            };
+
+            writeln!(
+                report,
+                "  {:>10} {:>5.1}%  {:X}:{:X} {} (line {})",
+                count,
+                percent(count, total),
+                index,
+                pc,
+                mnemonic,
+                line
+            )
+            .unwrap();
+        }
+
+        let mut natives: Vec<(&u64, &(&str, u64))> = self.natives.iter().collect();
+        natives.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+
+        write!(report, "Native procedure calls:").unwrap();
+        if natives.is_empty() {
+            write!(report, " none").unwrap();
+        }
+
+        for (index, (name, calls)) in natives {
+            write!(report, "\n  {:>10}  {} ({:X})", calls, name, index).unwrap();
+        }
+
+        report
+    }
+
+    // Writes one line per call stack in the folded format of flamegraph tools:
+    // procedure names from the outermost to the innermost separated by `;`,
+    // then the number of instructions executed with that stack.
+    pub fn write_folded(
+        &self,
+        procedures: &VecDeque<Procedure>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        for (node_index, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                continue;
+            }
+
+            let stack: Vec<String> = self
+                .path(node_index)
+                .into_iter()
+                .map(|index| procedure_name(procedures, index).replace(';', "_"))
+                .collect();
+
+            writeln!(output, "{} {}", stack.join(";"), node.count)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Hooks for Profiler {
+    fn before_instruction(&mut self, procedure: &Procedure, pc: u64, _stack: &VecDeque<u64>) {
+        // The entry procedure is not called by WYWOŁAJ
+        let current = match self.current {
+            Some(current) => current,
+            None => {
+                let root = self.enter(None, procedure.index);
+                *self.calls.entry(procedure.index).or_insert(0) += 1;
+                self.current = Some(root);
+                root
+            }
+        };
+
+        self.nodes[current].count += 1;
+        *self.instructions.entry((procedure.index, pc)).or_insert(0) += 1;
+    }
+
+    fn call(&mut self, _caller: &Procedure, _pc: u64, callee: &Procedure) {
+        self.current = Some(self.enter(self.current, callee.index));
+        *self.calls.entry(callee.index).or_insert(0) += 1;
+    }
+
+    fn ret(&mut self, _procedure: &Procedure) {
+        self.current = self.current.and_then(|current| self.nodes[current].parent);
+    }
+
+    fn native_call(&mut self, index: u64, name: &'static str, _stack: &VecDeque<u64>) {
+        self.natives.entry(index).or_insert((name, 0)).1 += 1;
+    }
+}
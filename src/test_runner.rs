@@ -0,0 +1,481 @@
+use crate::coverage::Coverage;
+use crate::{configure_vm, get_flag_path, or_exit, write_file};
+use libkrem::error::Info;
+use libkrem::natives::{Io, OutputBuffer};
+use libkrem::parse::Procedure;
+use libkrem::vm::{Status, Vm};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Expectations are written as whole-line comments in the tested file:
+//   ; expect-stdout: OK       one line of the expected output
+//   ; expect-stdout-no-newline: 42
+//                             the last line, when the output does not end
+//                             with a newline
+//   ; expect-stack: 1 d10     final value stack, numbers as in PCHNIJ
+//   ; expect-error: message   expected runtime error message
+//   ; expect-parse-error: 7 message
+//                             expected parsing error and its line, in decimal
+//   ; stdin: 42               one line of input for the program
+//   ; flags: --max-stack 10   command line flags for the VM
+// The main procedure is only checked if the file has an expect directive. A
+// file with expect-parse-error directives is only parsed, it must fail with
+// exactly those errors.
+// Procedures marked with @Test are run separately, the input and flags apply
+// to them as well.
+#[derive(Default)]
+struct Expectations {
+    stdout: Option<String>,
+    stack: Option<Vec<u64>>,
+    error: Option<String>,
+    parse_errors: Vec<(i32, String)>,
+    stdin: String,
+    flags: Vec<String>,
+}
+
+impl Expectations {
+    fn is_empty(&self) -> bool {
+        self.stdout.is_none()
+            && self.stack.is_none()
+            && self.error.is_none()
+            && self.parse_errors.is_empty()
+    }
+}
+
+fn read_expectations(content: &str) -> Result<Expectations, String> {
+    let mut expectations = Expectations::default();
+
+    for (line_idx, line) in content.lines().enumerate() {
+        let comment = match line.trim_start().strip_prefix(';') {
+            Some(comment) => comment.trim(),
+            None => continue,
+        };
+
+        let (directive, value) = match comment.split_once(':') {
+            Some((directive, value)) => (directive.trim(), value.trim()),
+            None => continue,
+        };
+
+        match directive {
+            "expect-stdout" | "expect-stdout-no-newline" => {
+                let stdout = expectations.stdout.get_or_insert_with(String::new);
+
+                if !stdout.is_empty() && !stdout.ends_with('\n') {
+                    return Err(format!(
+                        "line {}: expect-stdout-no-newline must be the last line of the output",
+                        line_idx + 1
+                    ));
+                }
+
+                stdout.push_str(value);
+
+                if directive == "expect-stdout" {
+                    stdout.push('\n');
+                }
+            }
+            "expect-stack" => {
+                let mut errors = VecDeque::new();
+                let position = libkrem::error::Position {
+                    line: line_idx as i32 + 1,
+                    column: 0,
+                };
+                let values = value
+                    .split_whitespace()
+                    .map(|value| {
+                        libkrem::parse::get_number_from_string(value, false, &position, &mut errors)
+                    })
+                    .collect();
+
+                if !errors.is_empty() {
+                    return Err(format!("line {}: invalid expect-stack value", line_idx + 1));
+                }
+
+                expectations.stack = Some(values);
+            }
+            "expect-error" => expectations.error = Some(value.to_owned()),
+            "expect-parse-error" => {
+                let parse_error = value.split_once(' ').and_then(|(line, message)| {
+                    Some((line.parse().ok()?, message.trim().to_owned()))
+                });
+
+                match parse_error {
+                    Some(parse_error) => expectations.parse_errors.push(parse_error),
+                    None => {
+                        return Err(format!(
+                            "line {}: expect-parse-error needs a line and a message",
+                            line_idx + 1
+                        ))
+                    }
+                }
+            }
+            "stdin" => {
+                expectations.stdin.push_str(value);
+                expectations.stdin.push('\n');
+            }
+            "flags" => expectations
+                .flags
+                .extend(value.split_whitespace().map(str::to_owned)),
+            _ => {}
+        }
+    }
+
+    Ok(expectations)
+}
+
+// Line diff of expected and actual text, based on the longest common
+// subsequence of lines.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = String::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            result.push_str(&format!("      {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            result.push_str(&format!("    - {}\n", expected[i]));
+            i += 1;
+        } else {
+            result.push_str(&format!("    + {}\n", actual[j]));
+            j += 1;
+        }
+    }
+
+    result
+}
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped,
+}
+
+// Fresh VM for a single test, with the input and flags of the file. Invalid
+// flags fail the tests of the file.
+fn build_vm(
+    procedures: &VecDeque<Procedure>,
+    expectations: &Expectations,
+    output: &OutputBuffer,
+) -> Result<Vm, String> {
+    let io = Io {
+        input: Box::new(io::Cursor::new(expectations.stdin.clone().into_bytes())),
+        output: Box::new(output.clone()),
+    };
+
+    let vm_builder = configure_vm(Vm::builder(procedures.clone()), &expectations.flags)
+        .map_err(|error| format!("  invalid flags: {}\n", error))?;
+
+    Ok(vm_builder.io(io).build())
+}
+
+// Runs the main procedure and checks it against the expect directives.
+fn check_program(
+    procedures: &VecDeque<Procedure>,
+    expectations: &Expectations,
+    coverage: &mut Option<Coverage>,
+) -> Outcome {
+    let output = OutputBuffer::default();
+    let mut vm = match build_vm(procedures, expectations, &output) {
+        Ok(vm) => vm,
+        Err(failure) => return Outcome::Failed(failure),
+    };
+
+    // (message, line) of the runtime error, if any
+    let error = match vm.run_with(coverage) {
+        Ok(Status::Halted) | Ok(Status::Paused) => None,
+        Ok(Status::OutOfFuel) => Some(("out of fuel".to_owned(), None)),
+        Ok(Status::WaitingForInput) => Some(("waiting for input".to_owned(), None)),
+        Err(error) => Some((error.kind.get_message(), Some(error.position.line))),
+    };
+
+    let stdout = output.take_string();
+    let mut failures = String::new();
+
+    match (&expectations.error, &error) {
+        (Some(expected), Some((message, _))) if expected == message => {}
+        (Some(expected), Some((message, _))) => failures.push_str(&format!(
+            "  expected runtime error: {}\n  got runtime error: {}\n",
+            expected, message
+        )),
+        (Some(expected), None) => failures.push_str(&format!(
+            "  expected runtime error: {}\n  the program halted without an error\n",
+            expected
+        )),
+        (None, Some((message, line))) => {
+            failures.push_str(&format!("  unexpected runtime error: {}", message));
+
+            if let Some(line) = line {
+                failures.push_str(&format!(" at line {}", line));
+            }
+
+            failures.push('\n');
+        }
+        (None, None) => {}
+    }
+
+    if let Some(expected) = &expectations.stdout {
+        if *expected != stdout {
+            failures.push_str("  stdout differs (- expected, + actual):\n");
+            failures.push_str(&diff(expected, &stdout));
+        }
+    }
+
+    if let Some(expected) = &expectations.stack {
+        let actual: Vec<u64> = vm.stack.iter().copied().collect();
+
+        if *expected != actual {
+            failures.push_str(&format!(
+                "  stack differs:\n    expected: {:?}\n    actual:   {:?}\n",
+                expected, actual
+            ));
+        }
+    }
+
+    if failures.is_empty() {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(failures)
+    }
+}
+
+// Runs a @Test procedure, it passes if it returns without a runtime error.
+fn run_test_procedure(
+    procedures: &VecDeque<Procedure>,
+    expectations: &Expectations,
+    index: u64,
+    coverage: &mut Option<Coverage>,
+) -> Outcome {
+    let output = OutputBuffer::default();
+    let mut vm = match build_vm(procedures, expectations, &output) {
+        Ok(vm) => vm,
+        Err(failure) => return Outcome::Failed(failure),
+    };
+
+    let mut failure = match vm.run_procedure_with(index, coverage) {
+        Ok(Status::Halted) | Ok(Status::Paused) => return Outcome::Passed,
+        Ok(Status::OutOfFuel) => "  out of fuel\n".to_owned(),
+        Ok(Status::WaitingForInput) => "  waiting for input\n".to_owned(),
+        Err(error) => {
+            let mut failure = format!(
+                "  {} at line {}\n",
+                error.kind.get_message(),
+                error.position.line
+            );
+
+            for (procedure, position) in vm.stack_trace() {
+                failure.push_str(&format!(
+                    "    in {} ({:X}) at line {}\n",
+                    procedure.name, procedure.index, position.line
+                ));
+            }
+
+            failure
+        }
+    };
+
+    let stdout = output.take_string();
+
+    if !stdout.is_empty() {
+        failure.push_str("  stdout:\n");
+
+        for line in stdout.lines() {
+            failure.push_str(&format!("    {}\n", line));
+        }
+    }
+
+    Outcome::Failed(failure)
+}
+
+// Checks the main program if the file has expect directives, then runs every
+// @Test procedure, each in a fresh VM. With `lcov`, the coverage of all of the
+// runs is appended to it as one record.
+fn run_file(path: &Path, lcov: &mut Option<Vec<u8>>) -> Vec<(String, Outcome)> {
+    let name = path.display().to_string();
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            let failure = format!("  cannot read the file: {}\n", error);
+            return vec![(name, Outcome::Failed(failure))];
+        }
+    };
+
+    let expectations = match read_expectations(&content) {
+        Ok(expectations) => expectations,
+        Err(error) => return vec![(name, Outcome::Failed(format!("  {}\n", error)))],
+    };
+
+    let cvma_file = libkrem::parse::read_from_string(&content);
+    let procedures = cvma_file.procedures;
+    let has_tests = procedures.iter().any(|procedure| procedure.is_test);
+
+    if expectations.is_empty() && !has_tests {
+        return vec![(name, Outcome::Skipped)];
+    }
+
+    if !expectations.parse_errors.is_empty() {
+        let actual: Vec<(i32, String)> = cvma_file
+            .errors
+            .iter()
+            .map(|error| (error.position.line, error.get_message().to_owned()))
+            .collect();
+
+        if actual == expectations.parse_errors {
+            return vec![(name, Outcome::Passed)];
+        }
+
+        let mut failure = String::from("  parsing errors differ:\n    expected:\n");
+        for (line, message) in &expectations.parse_errors {
+            failure.push_str(&format!("      line {}: {}\n", line, message));
+        }
+
+        failure.push_str("    actual:\n");
+        for (line, message) in &actual {
+            failure.push_str(&format!("      line {}: {}\n", line, message));
+        }
+
+        return vec![(name, Outcome::Failed(failure))];
+    }
+
+    if let Some(error) = cvma_file.errors.front() {
+        let failure = format!(
+            "  parsing error at line {}: {}\n",
+            error.position.line,
+            error.get_message()
+        );
+        return vec![(name, Outcome::Failed(failure))];
+    }
+
+    let mut outcomes = Vec::new();
+    let mut coverage = lcov.as_ref().map(|_| Coverage::new());
+
+    if !expectations.is_empty() {
+        outcomes.push((
+            name.clone(),
+            check_program(&procedures, &expectations, &mut coverage),
+        ));
+    }
+
+    for procedure in procedures.iter().filter(|procedure| procedure.is_test) {
+        outcomes.push((
+            format!("{}::{}", name, procedure.name),
+            run_test_procedure(&procedures, &expectations, procedure.index, &mut coverage),
+        ));
+    }
+
+    if let (Some(coverage), Some(lcov)) = (&coverage, lcov) {
+        // Writing to a Vec cannot fail
+        coverage.write_lcov(&name, &procedures, lcov).unwrap();
+    }
+
+    outcomes
+}
+
+fn find_tests(path: &Path, tests: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+
+            if !hidden && entry.file_name() != "target" {
+                find_tests(&entry_path, tests);
+            }
+        }
+    } else if path
+        .extension()
+        .is_some_and(|extension| extension == "cvma")
+    {
+        tests.push(path.to_owned());
+    }
+}
+
+// Runs every .cvma file under the paths in `args`, returns true if all of the
+// tests passed. `--coverage file` writes the coverage of the tests as lcov.
+pub fn run(args: &[String]) -> bool {
+    let coverage_path = or_exit(get_flag_path(args, "--coverage"));
+    let mut lcov = coverage_path.as_ref().map(|_| Vec::new());
+
+    let mut paths = args.to_vec();
+    if let Some(position) = paths.iter().position(|arg| arg == "--coverage") {
+        paths.drain(position..position + 2);
+    }
+
+    let mut tests = Vec::new();
+
+    if paths.is_empty() {
+        find_tests(Path::new("."), &mut tests);
+    } else {
+        for path in &paths {
+            // A mistyped path would otherwise pass with no tests
+            if !Path::new(path).exists() {
+                println!("Error: {}: no such file or directory", path);
+                return false;
+            }
+
+            find_tests(Path::new(path), &mut tests);
+        }
+    }
+
+    tests.sort();
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    let mut failures = Vec::new();
+
+    for (name, outcome) in tests.iter().flat_map(|test| run_file(test, &mut lcov)) {
+        match outcome {
+            Outcome::Passed => {
+                passed += 1;
+                println!("test {} ... ok", name);
+            }
+            Outcome::Failed(message) => {
+                failed += 1;
+                println!("test {} ... FAILED", name);
+                failures.push((name, message));
+            }
+            Outcome::Skipped => skipped += 1,
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+
+        for (name, message) in &failures {
+            print!("\n{}:\n{}", name, message);
+        }
+    }
+
+    if let (Some(path), Some(lcov)) = (&coverage_path, &lcov) {
+        write_file(path, |output| output.write_all(lcov));
+    }
+
+    println!(
+        "\ntest result: {}. {} passed; {} failed; {} skipped",
+        if failed == 0 { "ok" } else { "FAILED" },
+        passed,
+        failed,
+        skipped
+    );
+
+    failed == 0
+}